@@ -1,6 +1,7 @@
-use crate::common::{BTREE_PAGE_SIZE, HEADER};
+use crate::common::HEADER;
 use crate::little_endian::LittleEndian;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Eq, PartialEq, Debug)]
 pub enum BType {
     Node = 1,
@@ -15,10 +16,8 @@ pub struct BNode {
 // Basic
 impl BNode {
     pub fn new_with_cap(size: usize) -> BNode {
-        let mut v = Vec::new();
-        v.resize(size, 0);
         BNode {
-            data: v,
+            data: vec![0; size],
         }
     }
     pub fn new_with_data(data: Vec<u8>) -> BNode {
@@ -99,14 +98,41 @@ impl BNode {
     }
     fn byte_copy(&mut self, start: u16, val: &[u8]) {
         assert!(start as usize + val.len() <= self.data.len());
-        for i in 0..val.len() {
-            self.data[start as usize + i] = val[i];
-        }
+        self.data[start as usize..start as usize + val.len()].copy_from_slice(val);
     }
     pub fn get_bytes(&self, start: u16, end: u16) -> &[u8] {
         assert!(end as usize <= self.data.len());
         &self.data[start as usize..end as usize]
     }
+
+    // check a raw page before trusting its offsets
+    pub fn check_layout(&self) -> Result<(), String> {
+        if self.data.len() < HEADER {
+            return Err(String::from("page shorter than header"));
+        }
+        let n_type = self.read_u16(0);
+        if n_type != BType::Node as u16 && n_type != BType::LEAF as u16 {
+            return Err(format!("bad node type {}", n_type));
+        }
+        let n_keys = self.n_keys() as usize;
+        let kv_begin = HEADER + 10 * n_keys;
+        if kv_begin > self.data.len() {
+            return Err(format!("{} keys overflow the page", n_keys));
+        }
+        let mut pos = kv_begin;
+        for i in 1..=n_keys {
+            if pos + 4 > self.data.len() {
+                return Err(format!("kv {} out of page", i - 1));
+            }
+            let k_len = self.read_u16(pos) as usize;
+            let v_len = self.read_u16(pos + 2) as usize;
+            pos += 4 + k_len + v_len;
+            if pos > self.data.len() || kv_begin + self.get_offset(i as u16) as usize != pos {
+                return Err(format!("kv {} has bad offset", i - 1));
+            }
+        }
+        Ok(())
+    }
 }
 
 // Domain
@@ -164,43 +190,60 @@ impl BNode {
         self.set_offset(idx + 1, self.get_offset(idx) + 4 + (key.len() + val.len()) as u16)
     }
 
-    // split the node to [1,2,3] nodes
-    pub fn split(&mut self) -> Vec<BNode> {
-        if self.n_bytes() <= BTREE_PAGE_SIZE as u16 {
-            self.resize(BTREE_PAGE_SIZE);
-            return vec![self.clone()];
+    // split the node into nodes that fit a page, two as even in bytes as they can be,
+    // more if a large kv leaves no two that fit
+    pub fn split(&self, page_size: usize) -> Vec<BNode> {
+        let n = self.n_keys();
+        if self.range_size(0, n) <= page_size {
+            return vec![self.range(page_size, 0, n)];
         }
-        let (mut left, right) = self.split2();
-        if left.n_bytes() <= BTREE_PAGE_SIZE as u16 {
-            left.resize(BTREE_PAGE_SIZE);
-            return vec![left, right];
+        if (1..n).any(|i| self.range_size(0, i) <= page_size && self.range_size(i, n) <= page_size) {
+            return self.split_even(page_size);
         }
-        let (left, middle) = left.split2();
-        assert!(left.n_bytes() <= BTREE_PAGE_SIZE as u16);
-        vec![left, middle, right]
-    }
-    fn split2(&mut self) -> (BNode, BNode) {
-        let mut left = BNode::new_with_cap(2 * BTREE_PAGE_SIZE);
-        let mut right = BNode::new_with_cap(BTREE_PAGE_SIZE);
-
-        let mut idx = self.n_keys() - 1;
+        self.split_fill(page_size, page_size / 2)
+    }
+    // split the node into nodes that fit a page, filling each but the last
+    // up to `fill` bytes; a full left page suits appends at the right end
+    pub fn split_fill(&self, page_size: usize, fill: usize) -> Vec<BNode> {
+        assert!(fill <= page_size);
+        let n = self.n_keys();
+        let mut nodes = Vec::new();
+        let mut start = 0;
         loop {
-            let nk = self.n_keys() - idx;
-            let kv_size = self.get_offset(self.n_keys()) - self.get_offset(idx);
-            let size = HEADER as u16 + 8 * nk + 2 * nk + kv_size;
-            if size >= BTREE_PAGE_SIZE as u16 { break; }
-            idx -= 1;
+            if self.range_size(start, n) <= page_size {
+                nodes.push(self.range(page_size, start, n));
+                return nodes;
+            }
+            // one kv at least, the rest is over a page so it is never all of them
+            let mut end = start + 1;
+            while self.range_size(start, end + 1) <= fill {
+                end += 1;
+            }
+            nodes.push(self.range(page_size, start, end));
+            start = end;
         }
+    }
+    // bytes of a node of the kvs [from, to)
+    fn range_size(&self, from: u16, to: u16) -> usize {
+        let nk = (to - from) as usize;
+        HEADER + 10 * nk + (self.get_offset(to) - self.get_offset(from)) as usize
+    }
+    fn range(&self, page_size: usize, from: u16, to: u16) -> BNode {
+        let mut node = BNode::new_with_cap(page_size);
+        node.set_header(self.n_type(), to - from);
+        node.copy_range(self, 0, from, to - from);
+        node
+    }
 
-        // [0, idx]
-        left.set_header(self.n_type(), idx + 1);
-        left.copy_range(self, 0, 0, idx + 1);
-
-        // [idx+1, n_keys-1]
-        right.set_header(self.n_type(), self.n_keys() - idx - 1);
-        right.copy_range(self, 0, idx + 1, self.n_keys() - idx - 1);
-
-        (left, right)
+    // split into two nodes that fit a page, as even in bytes as they can be
+    pub fn split_even(&self, page_size: usize) -> Vec<BNode> {
+        let n = self.n_keys();
+        let size = |from: u16, to: u16| self.range_size(from, to);
+        let idx = (1..n)
+            .filter(|i| size(0, *i) <= page_size && size(*i, n) <= page_size)
+            .min_by_key(|i| size(0, *i).abs_diff(size(*i, n)))
+            .unwrap();
+        vec![self.range(page_size, 0, idx), self.range(page_size, idx, n)]
     }
 
     // merge a node
@@ -227,7 +270,7 @@ impl LittleEndian for BNode {
 
 #[cfg(test)]
 mod tests {
-    use crate::common::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE};
+    use crate::common::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE, BTREE_PAGE_SIZE, max_key_size, max_val_size};
 
     use super::*;
 
@@ -278,7 +321,7 @@ mod tests {
 
     #[test]
     fn test_kv() {
-        let mut node = BNode::new_with_data(basic_data());
+        let node = BNode::new_with_data(basic_data());
         assert_eq!(node.get_key(0), &[0xac]);
         assert_eq!(node.get_val(0), &[0xac]);
     }
@@ -291,6 +334,20 @@ mod tests {
         assert_eq!(node.get_bytes(0, 2), &[0x02, 00]);
     }
 
+    #[test]
+    fn test_check_layout() {
+        assert!(BNode::new_with_data(basic_data()).check_layout().is_ok());
+        let mut data = basic_data();
+        data[12] = 0x07;
+        assert!(BNode::new_with_data(data).check_layout().is_err());
+        let mut data = basic_data();
+        data[2] = 0xff;
+        assert!(BNode::new_with_data(data).check_layout().is_err());
+        let mut data = basic_data();
+        data[0] = 0x05;
+        assert!(BNode::new_with_data(data).check_layout().is_err());
+    }
+
     /* Domain test */
     fn domain_data() -> Vec<u8> {
         vec![0x01, 0x00, // type
@@ -306,7 +363,7 @@ mod tests {
 
     #[test]
     fn test_look_up() {
-        let mut node = BNode::new_with_data(domain_data());
+        let node = BNode::new_with_data(domain_data());
         assert_eq!(node.n_keys(), 2);
         assert_eq!(node.lookup_le(&[0x90]), 0);
         assert_eq!(node.lookup_le(&[0x9c]), 0);
@@ -317,7 +374,7 @@ mod tests {
 
     #[test]
     fn test_copy_range() {
-        let mut old = BNode::new_with_data(domain_data());
+        let old = BNode::new_with_data(domain_data());
         let mut new = BNode::new_with_cap(old.n_bytes() as usize);
         new.set_header(old.n_type(), old.n_keys());
         new.copy_range(&old, 0, 0, old.n_keys());
@@ -345,7 +402,7 @@ mod tests {
         node.insert_kv(0, 0, &[0x11; BTREE_MAX_KEY_SIZE], &[0x11; BTREE_MAX_VAL_SIZE]);
         node.insert_kv(1, 0, &[0x22; BTREE_MAX_KEY_SIZE], &[0x22; BTREE_MAX_VAL_SIZE]);
         node.insert_kv(2, 0, &[0x33; BTREE_MAX_KEY_SIZE], &[0x33; BTREE_MAX_VAL_SIZE]);
-        let v = node.split();
+        let v = node.split(BTREE_PAGE_SIZE);
         assert_eq!(v.len(), 3);
         assert_eq!(v[0].get_key(0), &[0x11; BTREE_MAX_KEY_SIZE]);
         assert_eq!(v[0].get_val(0), &[0x11; BTREE_MAX_VAL_SIZE]);
@@ -355,10 +412,66 @@ mod tests {
         assert_eq!(v[2].get_val(0), &[0x33; BTREE_MAX_VAL_SIZE]);
     }
 
+    #[test]
+    fn test_split_page_size() {
+        let (k, v) = (max_key_size(1024), max_val_size(1024));
+        let mut node = BNode::new_with_cap(5 * 1024);
+        node.set_header(BType::LEAF, 5);
+        for i in 0..5u8 {
+            node.insert_kv(i as u16, 0, &vec![i; k], &vec![i; v]);
+        }
+        let nodes = node.split(1024);
+        assert_eq!(nodes.len(), 5);
+        for (i, node) in nodes.iter().enumerate() {
+            assert!(node.n_bytes() <= 1024);
+            assert_eq!(node.get_key(0), &vec![i as u8; k][..]);
+        }
+        assert_eq!(node.split(4096).len(), 2);
+    }
+
+    #[test]
+    fn test_split_fill() {
+        let mut node = BNode::new_with_cap(2 * BTREE_PAGE_SIZE);
+        node.set_header(BType::LEAF, 40);
+        for i in 0..40u8 {
+            node.insert_kv(i as u16, 0, &[i], &[i; 100]);
+        }
+        // 114 bytes a kv, 35 fill a page
+        let nodes = node.split_fill(BTREE_PAGE_SIZE, BTREE_PAGE_SIZE);
+        assert_eq!((nodes[0].n_keys(), nodes[1].n_keys()), (35, 5));
+        let nodes = node.split(BTREE_PAGE_SIZE);
+        assert_eq!((nodes[0].n_keys(), nodes[1].n_keys()), (20, 20));
+        // a kv each on the left till the rest fits
+        let nodes = node.split_fill(BTREE_PAGE_SIZE, 200);
+        assert_eq!(nodes.iter().map(|n| n.n_keys()).collect::<Vec<_>>(), vec![1, 1, 1, 1, 1, 35]);
+    }
+
+    #[test]
+    fn test_split_even() {
+        let mut node = BNode::new_with_cap(2 * BTREE_PAGE_SIZE);
+        node.set_header(BType::LEAF, 40);
+        for i in 0..40u8 {
+            node.insert_kv(i as u16, 0, &[i], &[i; 100]);
+        }
+        let nodes = node.split_even(BTREE_PAGE_SIZE);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!((nodes[0].n_keys(), nodes[1].n_keys()), (20, 20));
+        assert_eq!(nodes[1].get_key(0), &[20]);
+
+        // the big kv keeps a side to itself
+        let mut node = BNode::new_with_cap(2 * BTREE_PAGE_SIZE);
+        node.set_header(BType::LEAF, 3);
+        node.insert_kv(0, 0, &[0], &[0; 10]);
+        node.insert_kv(1, 0, &[1], &[1; 10]);
+        node.insert_kv(2, 0, &[2], &vec![2; BTREE_MAX_VAL_SIZE]);
+        let nodes = node.split_even(BTREE_PAGE_SIZE);
+        assert_eq!((nodes[0].n_keys(), nodes[1].n_keys()), (2, 1));
+    }
+
     #[test]
     fn test_merge() {
-        let mut node1 = BNode::new_with_data(domain_data());
-        let mut node2 = BNode::new_with_data(domain_data());
+        let node1 = BNode::new_with_data(domain_data());
+        let node2 = BNode::new_with_data(domain_data());
         let mut node3 = BNode::new_with_cap(0);
         node3.merge(&node1, &node2);
         assert_eq!(node3.n_keys(), node1.n_keys() + node2.n_keys());
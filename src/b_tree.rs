@@ -1,23 +1,133 @@
-use crate::b_node::{BNode, BType};
-use crate::common::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE, BTREE_PAGE_SIZE, HEADER, Persist};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
-struct BTree {
+use crate::b_node::{BNode, BType};
+use crate::b_tree::merge::MergeOperator;
+use crate::b_tree::merkle::Hash;
+use crate::b_tree::subscribe::{Event, Subscription};
+use crate::common::{HEADER, max_key_size, max_val_size, Persist};
+use crate::kv::Durability;
+use crate::kv::backup::Snapshot;
+
+pub mod blob;
+mod check;
+mod compact;
+mod count;
+pub mod diff;
+mod dup;
+pub mod iter;
+pub mod merge;
+pub mod merkle;
+pub mod subscribe;
+pub mod sync;
+pub mod view;
+
+pub struct BTree {
     root: u64,
     persist: Box<dyn Persist>,
+    page_size: usize,
+    // ops since begin() wait for commit() to be flushed
+    in_txn: bool,
+    // merkle hashes of the pages whose parents do not hold them,
+    // pages do not change while in use
+    hashes: RefCell<HashMap<u64, Hash>>,
+    // inner entries keep the key count and merkle hash of their kid
+    augmented: bool,
+    // percent of a page left in the left node when an append splits it
+    fill_factor: usize,
+    // combines merge operands with the stored vals
+    merge_op: Option<Box<dyn MergeOperator>>,
+    subscriptions: Vec<Subscription>,
+    // changes to watched keys, sent once committed
+    events: Vec<Event>,
+}
+
+// what an insert puts in the leaf
+enum Write<'a> {
+    Val(&'a [u8]),
+    // an operand for the merge operator, applied to the val found in the leaf
+    Merge(&'a [u8]),
 }
 
 impl BTree {
     pub fn new(persist: Box<dyn Persist>) -> Self {
         BTree {
             root: persist.get_root(),
+            page_size: persist.page_size(),
             persist,
+            in_txn: false,
+            hashes: RefCell::new(HashMap::new()),
+            augmented: false,
+            fill_factor: 100,
+            merge_op: None,
+            subscriptions: Vec::new(),
+            events: Vec::new(),
         }
     }
 
+    // the root ops so far have made, committed or not
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn max_key_size(&self) -> usize {
+        max_key_size(self.page_size)
+    }
+    pub fn max_val_size(&self) -> usize {
+        max_val_size(self.page_size)
+    }
+
+    // how full appends leave the pages they split, in percent of a page;
+    // below 100 leaves room for updates in place
+    pub fn set_fill_factor(&mut self, percent: usize) {
+        assert!((50..=100).contains(&percent));
+        self.fill_factor = percent;
+    }
+
+    // keep the key count and merkle hash of each kid in its entry from now on, so counts,
+    // ranks, root hashes, proofs and syncs only read the nodes on their paths; every write
+    // hashes the nodes it copies and inner entries grow by a count and a hash. off, the
+    // entries are as in a plain tree, counts walk the tree and hashes are computed when
+    // asked for and kept in memory
+    pub fn set_augmented(&mut self, on: bool) {
+        self.augmented = on;
+    }
+
+    // pin the last committed state, see Snapshot::backup
+    pub fn snapshot(&mut self) -> Result<Snapshot, String> {
+        self.persist.snapshot()
+    }
+
+    // group the following ops into one commit
+    pub fn begin(&mut self) {
+        self.in_txn = true;
+    }
+    pub fn commit(&mut self) {
+        self.in_txn = false;
+        self.flush();
+    }
+
+    // how commits reach the disk from now on, see Durability
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.persist.set_durability(durability);
+    }
+    // commit with a durability of its own
+    pub fn commit_with(&mut self, durability: Durability) {
+        let old = self.persist.durability();
+        self.persist.set_durability(durability);
+        self.commit();
+        self.persist.set_durability(old);
+    }
+    // put the deferred commits on disk
+    pub fn sync(&mut self) {
+        self.persist.sync();
+    }
+
     // get a key from root
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         assert_ne!(key.len(), 0);
-        assert!(key.len() <= BTREE_MAX_KEY_SIZE);
+        assert!(key.len() <= self.max_key_size());
         if self.root == 0 {
             return None;
         }
@@ -28,61 +138,124 @@ impl BTree {
     // delete a key from root
     pub fn delete(&mut self, key: &[u8]) -> bool {
         assert_ne!(key.len(), 0);
-        assert!(key.len() <= BTREE_MAX_KEY_SIZE);
+        assert!(key.len() <= self.max_key_size());
         if self.root == 0 {
             return false;
         }
 
+        let old = self.watch(key);
         let k_node = self.persist.get_node(self.root);
         let r = self.tree_delete(&k_node, key);
         match r {
             None => false,
             Some(node) => {
-                self.persist.del_node(self.root);
+                self.del_node(self.root);
                 if node.n_type() == BType::Node && node.n_keys() == 1 {
                     self.root = node.get_ptr(0);
                 } else {
-                    self.root = self.persist.new_node(&node);
+                    // a longer separator key can make it grow
+                    self.root = self.new_root(&node.split(self.page_size));
                 }
-                self.persist.set_root(self.root);
-                self.persist.flush();
+                self.changed(key, old);
+                self.flush();
                 true
             }
         }
     }
     // insert a key from root
     pub fn insert(&mut self, key: &[u8], val: &[u8]) {
+        assert!(val.len() <= self.max_val_size());
+        let old = self.watch(key);
+        // a plain val does not fail
+        self.upsert(key, Write::Val(val)).unwrap();
+        self.changed(key, old);
+        self.flush();
+    }
+    // combine the operand with the key's val by the merge operator and write it like
+    // an insert; fails, changing nothing, if there is no operator or the merged val
+    // does not fit
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), String> {
+        let old = self.watch(key);
+        self.upsert(key, Write::Merge(operand))?;
+        self.changed(key, old);
+        self.flush();
+        Ok(())
+    }
+    fn upsert(&mut self, key: &[u8], write: Write) -> Result<(), String> {
         assert_ne!(key.len(), 0);
-        assert!(key.len() <= BTREE_MAX_KEY_SIZE);
-        assert!(val.len() <= BTREE_MAX_VAL_SIZE);
+        assert!(key.len() <= self.max_key_size());
 
         if self.root == 0 {
-            let mut root_node = BNode::new_with_cap(BTREE_PAGE_SIZE);
+            let val = self.leaf_val(key, None, write)?;
+            let mut root_node = BNode::new_with_cap(self.page_size);
             root_node.set_header(BType::LEAF, 2);
             root_node.insert_kv(0, 0, &[], &[]);
-            root_node.insert_kv(1, 0, key, val);
+            root_node.insert_kv(1, 0, key, &val);
             self.root = self.persist.new_node(&root_node);
-            return;
+            return Ok(());
         }
 
         let old = self.persist.get_node(self.root);
-        self.persist.del_node(self.root);
-
-        let childs = self.tree_insert(&old, key, val).split();
-        if childs.len() > 1 {
-            let mut root_node = BNode::new_with_cap(BTREE_PAGE_SIZE);
-            root_node.set_header(BType::Node, childs.len() as u16);
-            for i in 0..childs.len() as u16 {
-                let key = childs[i as usize].get_key(0);
-                let ptr = self.persist.new_node(&childs[i as usize]);
-                root_node.insert_kv(i, ptr, key, &[]);
+        let new = self.tree_insert(&old, key, write, true)?;
+        self.del_node(self.root);
+        let childs = self.split(&new, key, true);
+        self.root = self.new_root(&childs);
+        Ok(())
+    }
+
+    // the val the leaf gets, from the old one if merging
+    fn leaf_val<'a>(&self, key: &[u8], old: Option<&[u8]>, write: Write<'a>) -> Result<Cow<'a, [u8]>, String> {
+        match write {
+            Write::Val(val) => Ok(Cow::Borrowed(val)),
+            Write::Merge(operand) => {
+                let op = self.merge_op.as_ref().ok_or("no merge operator")?;
+                let val = op.merge(key, old, operand);
+                if val.len() > self.max_val_size() {
+                    return Err(format!("merged val of {} bytes", val.len()));
+                }
+                Ok(Cow::Owned(val))
             }
-            self.root = self.persist.new_node(&root_node);
+        }
+    }
+
+    // a node grown by inserting `key`; if the key went past the last key of the tree,
+    // keys are likely appended in order, so the left pages are left full instead of half
+    // full. `edge` is whether the node is on the rightmost path
+    fn split(&self, node: &BNode, key: &[u8], edge: bool) -> Vec<BNode> {
+        if edge && node.lookup_le(key) + 1 == node.n_keys() {
+            node.split_fill(self.page_size, self.page_size * self.fill_factor / 100)
         } else {
-            self.root = self.persist.new_node(&childs[0]);
+            node.split(self.page_size)
+        }
+    }
+
+    // the page may be reused from now on, its hash goes with it
+    fn del_node(&mut self, ptr: u64) {
+        self.hashes.get_mut().remove(&ptr);
+        self.persist.del_node(ptr);
+    }
+
+    fn flush(&mut self) {
+        if !self.in_txn {
+            self.persist.set_root(self.root);
+            self.persist.flush();
+            self.notify();
         }
-        self.persist.set_root(self.root);
-        self.persist.flush();
+    }
+
+    // persist the split nodes, adding a level if there are several
+    fn new_root(&mut self, childs: &[BNode]) -> u64 {
+        if childs.len() == 1 {
+            return self.persist.new_node(&childs[0]);
+        }
+        let mut root_node = BNode::new_with_cap(self.page_size);
+        root_node.set_header(BType::Node, childs.len() as u16);
+        for i in 0..childs.len() as u16 {
+            let key = childs[i as usize].get_key(0);
+            let ptr = self.persist.new_node(&childs[i as usize]);
+            root_node.insert_kv(i, ptr, key, &self.kid_val(&childs[i as usize]));
+        }
+        self.persist.new_node(&root_node)
     }
 
     // get a kv from a node
@@ -104,22 +277,26 @@ impl BTree {
         }
     }
     // insert a kv from a node
-    fn tree_insert(&mut self, node: &BNode, key: &[u8], val: &[u8]) -> BNode {
-        let mut new_node = BNode::new_with_cap(2 * BTREE_PAGE_SIZE);
+    // `edge` is whether the node is on the rightmost path
+    // fails before any node of the path is replaced
+    fn tree_insert(&mut self, node: &BNode, key: &[u8], write: Write, edge: bool) -> Result<BNode, String> {
+        let mut new_node = BNode::new_with_cap(2 * self.page_size);
 
         let idx = node.lookup_le(key);
         match node.n_type() {
             BType::LEAF => {
                 if key.cmp(node.get_key(idx)).is_eq() {
-                    self.leaf_update(&mut new_node, node, idx, key, val);
+                    let val = self.leaf_val(key, Some(node.get_val(idx)), write)?;
+                    self.leaf_update(&mut new_node, node, idx, key, &val);
                 } else {
-                    self.leaf_insert(&mut new_node, node, idx + 1, key, val);
+                    let val = self.leaf_val(key, None, write)?;
+                    self.leaf_insert(&mut new_node, node, idx + 1, key, &val);
                 }
             }
-            BType::Node => self.node_insert(&mut new_node, node, idx, key, val),
+            BType::Node => self.node_insert(&mut new_node, node, idx, key, write, edge)?,
         };
 
-        new_node
+        Ok(new_node)
     }
     // delete a kv from a node
     fn tree_delete(&mut self, node: &BNode, key: &[u8]) -> Option<BNode> {
@@ -129,7 +306,7 @@ impl BTree {
                 if key.cmp(node.get_key(idx)).is_ne() {
                     None
                 } else {
-                    let mut new = BNode::new_with_cap(BTREE_PAGE_SIZE);
+                    let mut new = BNode::new_with_cap(self.page_size);
                     self.leaf_delete(&mut new, node, idx);
                     Some(new)
                 }
@@ -158,106 +335,154 @@ impl BTree {
     }
 
     // node
-    fn node_insert(&mut self, new: &mut BNode, old: &BNode, idx: u16, key: &[u8], val: &[u8]) {
+    fn node_insert(&mut self, new: &mut BNode, old: &BNode, idx: u16, key: &[u8], write: Write, edge: bool) -> Result<(), String> {
         // get next level node
         let k_ptr = old.get_ptr(idx);
         let mut k_node = self.persist.get_node(k_ptr);
-        self.persist.del_node(k_ptr);
+        let edge = edge && idx + 1 == old.n_keys();
         // insert
-        k_node = self.tree_insert(&mut k_node, key, val);
+        k_node = self.tree_insert(&k_node, key, write, edge)?;
+        self.del_node(k_ptr);
         // split
-        let childs = k_node.split();
+        let childs = self.split(&k_node, key, edge);
         // update
         self.node_replace_n_kid(new, old, idx, &childs);
+        Ok(())
     }
     fn node_delete(&mut self, node: &BNode, idx: u16, key: &[u8]) -> Option<BNode> {
         let k_ptr = node.get_ptr(idx);
         let k_node = self.persist.get_node(k_ptr);
         let update_node = self.tree_delete(&k_node, key)?;
 
-        self.persist.del_node(k_ptr);
+        self.del_node(k_ptr);
 
-        let mut new = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        // separators may get longer, so the node may need a split
+        let mut new = BNode::new_with_cap(2 * self.page_size);
         match self.should_merge(node, &update_node, idx) {
             Some((dir, sibling)) => {
-                let mut merged_child = BNode::new_with_cap(BTREE_PAGE_SIZE);
+                let mut merged_child = BNode::new_with_cap(self.page_size);
                 if dir < 0 {
                     merged_child.merge(&sibling, &update_node);
-                    self.persist.del_node(node.get_ptr(idx - 1));
+                    self.del_node(node.get_ptr(idx - 1));
                     let ptr = self.persist.new_node(&merged_child);
-                    self.node_replace_2_kid(&mut new, node, idx - 1, ptr, merged_child.get_key(0));
+                    self.node_replace_2_kid(&mut new, node, idx - 1, ptr, &merged_child);
                 } else {
                     merged_child.merge(&update_node, &sibling);
-                    self.persist.del_node(node.get_ptr(idx + 1));
+                    self.del_node(node.get_ptr(idx + 1));
                     let ptr = self.persist.new_node(&merged_child);
-                    self.node_replace_2_kid(&mut new, node, idx, ptr, merged_child.get_key(0));
+                    self.node_replace_2_kid(&mut new, node, idx, ptr, &merged_child);
                 }
             }
-            None => {
-                assert!(update_node.n_keys() > 0);
-                self.node_replace_n_kid(&mut new, node, idx, &vec![update_node]);
+            None if update_node.n_keys() == 0 => {
+                // an empty kid without sibling, the parent goes empty too
+                assert!(idx == 0 && node.n_keys() == 1);
+                new.set_header(BType::Node, 0);
             }
+            None => match self.should_borrow(node, &update_node, idx) {
+                // too big to merge, even out the two instead
+                Some((dir, sibling)) => {
+                    let at = if dir < 0 { idx - 1 } else { idx };
+                    let mut both = BNode::new_with_cap(2 * self.page_size);
+                    if dir < 0 {
+                        both.merge(&sibling, &update_node);
+                    } else {
+                        both.merge(&update_node, &sibling);
+                    }
+                    self.del_node(node.get_ptr(if dir < 0 { idx - 1 } else { idx + 1 }));
+                    self.node_replace_kids(&mut new, node, at, 2, &both.split_even(self.page_size));
+                }
+                None => self.node_replace_n_kid(&mut new, node, idx, &update_node.split(self.page_size)),
+            },
         }
         Some(new)
     }
-    fn node_replace_n_kid(&mut self, new: &mut BNode, old: &BNode, idx: u16, childs: &Vec<BNode>) {
-        new.set_header(BType::Node, old.n_keys() + childs.len() as u16 - 1);
+    fn node_replace_n_kid(&mut self, new: &mut BNode, old: &BNode, idx: u16, childs: &[BNode]) {
+        self.node_replace_kids(new, old, idx, 1, childs);
+    }
+    // the `n` kids from idx on give way to the childs
+    fn node_replace_kids(&mut self, new: &mut BNode, old: &BNode, idx: u16, n: u16, childs: &[BNode]) {
+        new.set_header(BType::Node, old.n_keys() + childs.len() as u16 - n);
         new.copy_range(old, 0, 0, idx);
         for i in 0..childs.len() as u16 {
-            new.insert_kv(idx + i, self.persist.new_node(&childs[i as usize]), childs[i as usize].get_key(0), &[]);
+            let child = &childs[i as usize];
+            let val = self.kid_val(child);
+            new.insert_kv(idx + i, self.persist.new_node(child), child.get_key(0), &val);
         }
-        new.copy_range(old, idx + childs.len() as u16, idx + 1, old.n_keys() - (idx + 1));
+        new.copy_range(old, idx + childs.len() as u16, idx + n, old.n_keys() - (idx + n));
     }
-    fn node_replace_2_kid(&self, new: &mut BNode, old: &BNode, idx: u16, ptr: u64, key: &[u8]) {
+    fn node_replace_2_kid(&self, new: &mut BNode, old: &BNode, idx: u16, ptr: u64, kid: &BNode) {
         new.set_header(BType::Node, old.n_keys() - 1);
         new.copy_range(old, 0, 0, idx);
-        new.insert_kv(idx, ptr, key, &[]);
+        new.insert_kv(idx, ptr, kid.get_key(0), &self.kid_val(kid));
         new.copy_range(old, idx + 1, idx + 2, old.n_keys() - (idx + 2));
     }
 
     // help
     fn should_merge(&self, parent: &BNode, child: &BNode, idx: u16) -> Option<(i8, BNode)> {
-        if child.n_bytes() > BTREE_PAGE_SIZE as u16 / 4 {
+        if child.n_bytes() as usize > self.page_size / 4 {
             return None;
         }
 
         if idx > 0 {
             let sibling = self.persist.get_node(parent.get_ptr(idx - 1));
-            if sibling.n_bytes() + child.n_bytes() - HEADER as u16 <= BTREE_PAGE_SIZE as u16 {
+            if sibling.n_bytes() as usize + child.n_bytes() as usize - HEADER <= self.page_size {
                 return Some((-1, sibling));
             }
         }
 
         if idx + 1 < parent.n_keys() {
             let sibling = self.persist.get_node(parent.get_ptr(idx + 1));
-            if sibling.n_bytes() + child.n_bytes() - HEADER as u16 <= BTREE_PAGE_SIZE as u16 {
+            if sibling.n_bytes() as usize + child.n_bytes() as usize - HEADER <= self.page_size {
                 return Some((1, sibling));
             }
         }
 
         None
     }
+    // an underfull child next to a sibling too full to merge with
+    fn should_borrow(&self, parent: &BNode, child: &BNode, idx: u16) -> Option<(i8, BNode)> {
+        if child.n_bytes() as usize > self.page_size / 4 {
+            return None;
+        }
+        if idx > 0 {
+            return Some((-1, self.persist.get_node(parent.get_ptr(idx - 1))));
+        }
+        if idx + 1 < parent.n_keys() {
+            return Some((1, self.persist.get_node(parent.get_ptr(idx + 1))));
+        }
+        None
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+pub(crate) mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs::remove_file;
+
+    use crate::common::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE, BTREE_PAGE_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+    use crate::kv::{KV, Storage};
 
     use super::*;
 
     // mock persist
-    struct MockPersist {
+    pub struct MockPersist {
         pages: HashMap<u64, BNode>,
         incr: u64,
         root: u64,
+        page_size: usize,
     }
 
     impl MockPersist {
         pub fn new() -> Self {
+            Self::with_page_size(BTREE_PAGE_SIZE)
+        }
+
+        pub fn with_page_size(page_size: usize) -> Self {
             MockPersist {
                 pages: HashMap::new(),
                 incr: 0,
                 root: 0,
+                page_size,
             }
         }
     }
@@ -282,6 +507,14 @@ mod tests {
             self.pages.len()
         }
 
+        fn used(&self) -> u64 {
+            self.incr + 1
+        }
+
+        fn free_pages(&self) -> Vec<u64> {
+            (1..=self.incr).filter(|ptr| !self.pages.contains_key(ptr)).collect()
+        }
+
         fn get_root(&self) -> u64 {
             self.root
         }
@@ -291,6 +524,10 @@ mod tests {
         }
 
         fn flush(&mut self) {}
+
+        fn page_size(&self) -> usize {
+            self.page_size
+        }
     }
 
     // Mock db
@@ -316,6 +553,271 @@ mod tests {
         }
     }
 
+    // xorshift, enough for reproducible workloads
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        pub fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    // unique per id, a few ids use the largest key size
+    pub fn model_key(id: u64) -> Vec<u8> {
+        let len = match id % 16 {
+            0 => BTREE_MAX_KEY_SIZE,
+            1 => 100 + id as usize % 400,
+            _ => 4 + id as usize % 12,
+        };
+        let mut key = ((id as u32).wrapping_mul(0x9e3779b1)).to_be_bytes().to_vec();
+        key.resize(len, id as u8);
+        key
+    }
+
+    pub fn model_val(rng: &mut Rng) -> Vec<u8> {
+        let len = match rng.below(10) {
+            0 => BTREE_MAX_VAL_SIZE,
+            1 => rng.below(BTREE_MAX_VAL_SIZE as u64) as usize,
+            _ => rng.below(50) as usize,
+        };
+        vec![rng.next() as u8; len]
+    }
+
+    // a tree on the db file as it is
+    pub fn open(path: &str) -> BTree {
+        BTree::new(Box::new(KV::new(String::from(path)).unwrap()))
+    }
+
+    // a tree on a new db file
+    pub fn open_new(path: &str) -> BTree {
+        let _ = remove_file(path);
+        open(path)
+    }
+
+    // n keys in a scattered order in one commit, the i-th inserted gets val(i);
+    // returns what went in
+    pub fn fill(tree: &mut BTree, n: u32, val: impl Fn(u32) -> Vec<u8>) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut model = BTreeMap::new();
+        tree.begin();
+        for i in 0..n {
+            let key = (i * 7919 % n).to_be_bytes().to_vec();
+            tree.insert(&key, &val(i));
+            model.insert(key, val(i));
+        }
+        tree.commit();
+        model
+    }
+
+    // random ops against both the tree and a BTreeMap, `open` reopens the same db;
+    // keys and vals are cut to the limits of its page size
+    pub fn run_model(open: &dyn Fn() -> Box<dyn Persist>, seed: u64, n_ops: usize, n_ids: u64, reopen_every: usize) {
+        let mut rng = Rng(seed);
+        let mut model = BTreeMap::new();
+        let mut tree = BTree::new(open());
+        for i in 1..=n_ops {
+            let mut key = model_key(rng.below(n_ids));
+            key.truncate(tree.max_key_size());
+            match rng.below(20) {
+                0..=9 => {
+                    let mut val = model_val(&mut rng);
+                    val.truncate(tree.max_val_size());
+                    tree.insert(&key, &val);
+                    model.insert(key, val);
+                }
+                10..=14 => assert_eq!(tree.delete(&key), model.remove(&key).is_some(), "op {}", i),
+                15..=17 => assert_eq!(tree.get(&key), model.get(&key).cloned(), "op {}", i),
+                _ => {
+                    let n = rng.below(20) as usize;
+                    let got: Vec<_> = tree.scan(&key).take(n).collect();
+                    let want: Vec<_> = model.range(key..).take(n).map(|(k, v)| (k.clone(), v.clone())).collect();
+                    assert_eq!(got, want, "op {}", i);
+                }
+            }
+            if i % 100 == 0 {
+                assert_eq!(tree.check(), Ok(()), "op {}", i);
+            }
+            if reopen_every > 0 && i % reopen_every == 0 {
+                drop(tree);
+                tree = BTree::new(open());
+            }
+        }
+        let all: Vec<_> = tree.scan(&[0x00]).collect();
+        let want: Vec<_> = model.into_iter().collect();
+        assert_eq!(all, want);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_model_mock() {
+        for seed in 1..=8 {
+            run_model(&|| Box::new(MockPersist::new()), seed, 3000, 400, 0);
+        }
+    }
+
+    #[test]
+    fn test_model_kv() {
+        let _ = remove_file("test_model_kv.db");
+        let open = || Box::new(KV::new(String::from("test_model_kv.db")).unwrap()) as Box<dyn Persist>;
+        run_model(&open, 42, 3000, 300, 500);
+    }
+
+    #[test]
+    fn test_model_pool() {
+        let _ = remove_file("test_model_pool.db");
+        // small enough to evict all the time
+        let open = || Box::new(KV::open(String::from("test_model_pool.db"), Storage::Pool(16)).unwrap()) as Box<dyn Persist>;
+        run_model(&open, 43, 3000, 300, 500);
+    }
+
+    #[test]
+    fn test_model_page_size() {
+        for page_size in [MIN_PAGE_SIZE, MAX_PAGE_SIZE] {
+            run_model(&|| Box::new(MockPersist::with_page_size(page_size)), 5, 2000, 300, 0);
+        }
+        let _ = remove_file("test_model_page_size.db");
+        let open = || Box::new(KV::open_with_page_size(String::from("test_model_page_size.db"), Storage::Mmap, 2048).unwrap()) as Box<dyn Persist>;
+        run_model(&open, 6, 2000, 300, 400);
+        let _ = remove_file("test_model_page_size_pool.db");
+        let open = || Box::new(KV::open_with_page_size(String::from("test_model_page_size_pool.db"), Storage::Pool(16), 16384).unwrap()) as Box<dyn Persist>;
+        run_model(&open, 7, 2000, 300, 400);
+    }
+
+    #[test]
+    fn test_model_sequential() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        for i in 0..2000u32 {
+            tree.insert(&i.to_be_bytes(), &[0xca; 64]);
+        }
+        assert_eq!(tree.check(), Ok(()));
+        for i in (0..2000u32).rev().step_by(2) {
+            assert!(tree.delete(&i.to_be_bytes()));
+        }
+        assert_eq!(tree.check(), Ok(()));
+        for i in 0..2000u32 {
+            assert_eq!(tree.delete(&i.to_be_bytes()), i % 2 == 0);
+        }
+        assert_eq!(tree.check(), Ok(()));
+        assert_eq!(tree.scan(&[0x00]).count(), 0);
+    }
+
+    #[test]
+    fn test_borrow() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        // a full leaf next to one a delete away from a quarter page
+        let leaf = |keys: Vec<u32>| {
+            let mut node = BNode::new_with_cap(BTREE_PAGE_SIZE);
+            node.set_header(BType::LEAF, keys.len() as u16);
+            for (i, k) in keys.iter().enumerate() {
+                let key = if *k == 0 { vec![] } else { k.to_be_bytes().to_vec() };
+                node.insert_kv(i as u16, 0, &key, &[0xac; 100]);
+            }
+            node
+        };
+        let kids = [leaf((0..34).collect()), leaf((100..109).collect())];
+        let mut root = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        root.set_header(BType::Node, 2);
+        for (i, kid) in kids.iter().enumerate() {
+            let ptr = tree.persist.new_node(kid);
+            root.insert_kv(i as u16, ptr, kid.get_key(0), &(kid.n_keys() as u64).to_le_bytes());
+        }
+        tree.root = tree.persist.new_node(&root);
+        assert_eq!(tree.check(), Ok(()));
+
+        assert!(tree.delete(&100u32.to_be_bytes()));
+        assert_eq!(tree.check(), Ok(()));
+        // the right leaf takes entries from the left one
+        let root = tree.persist.get_node(tree.root);
+        assert_eq!(root.n_keys(), 2);
+        for i in 0..2 {
+            assert_eq!(tree.persist.get_node(root.get_ptr(i)).n_keys(), 21);
+        }
+        assert_eq!(root.get_key(1), 21u32.to_be_bytes());
+        assert_eq!(tree.scan(&[0x00]).count(), 41);
+    }
+
+    // leaves and their mean fill in percent of a page
+    fn leaf_fill(tree: &BTree) -> (usize, usize) {
+        let (mut leaves, mut bytes) = (0, 0);
+        let mut stack = vec![tree.root];
+        while let Some(ptr) = stack.pop() {
+            let node = tree.persist.get_node(ptr);
+            match node.n_type() {
+                BType::Node => stack.extend((0..node.n_keys()).map(|i| node.get_ptr(i))),
+                BType::LEAF => {
+                    leaves += 1;
+                    bytes += node.n_bytes() as usize;
+                }
+            }
+        }
+        (leaves, bytes * 100 / (leaves * tree.page_size))
+    }
+
+    #[test]
+    fn test_append_fill() {
+        let fill = |percent: usize, keys: &dyn Fn(u32) -> u32| {
+            let mut tree = BTree::new(Box::new(MockPersist::new()));
+            tree.set_fill_factor(percent);
+            for i in 0..3000u32 {
+                tree.insert(&keys(i).to_be_bytes(), &[0xac; 100]);
+            }
+            assert_eq!(tree.check(), Ok(()));
+            leaf_fill(&tree).1
+        };
+        // in key order the left pages stay full
+        assert!(fill(100, &|i| i) >= 95);
+        let f = fill(70, &|i| i);
+        assert!((65..=75).contains(&f), "{}", f);
+        // in any order they are at least half full
+        assert!(fill(100, &|i| i * 7919 % 3000) >= 50);
+        assert!(fill(100, &|i| 3000 - i) >= 50);
+    }
+
+    #[test]
+    fn test_append_inside() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        for i in 0..200u32 {
+            tree.insert(&(i * 1000).to_be_bytes(), &[0xac; 100]);
+        }
+        // past the last key of the first leaf, but not of the tree: an even split
+        let root = tree.persist.get_node(tree.root);
+        let first = tree.persist.get_node(root.get_ptr(0));
+        let last = u32::from_be_bytes(first.get_key(first.n_keys() - 1).try_into().unwrap());
+        tree.insert(&(last + 1).to_be_bytes(), &[0xac; 100]);
+        let root = tree.persist.get_node(tree.root);
+        let leaf = tree.persist.get_node(root.get_ptr(root.lookup_le(&(last + 1).to_be_bytes())));
+        assert!(leaf.n_keys() > first.n_keys() / 4, "{} of {}", leaf.n_keys(), first.n_keys());
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_txn() {
+        let _ = remove_file("test_txn.db");
+        let open = || BTree::new(Box::new(KV::new(String::from("test_txn.db")).unwrap()));
+        let mut tree = open();
+        tree.insert(&[0x01], &[0x01]);
+        tree.begin();
+        for i in 2..100u8 {
+            tree.insert(&[i], &[i; 200]);
+        }
+        tree.delete(&[0x01]);
+        assert_eq!(tree.get(&[0x50]), Some(vec![0x50; 200]));
+        assert_eq!(open().scan(&[0x00]).count(), 1);
+
+        tree.commit();
+        assert_eq!(tree.check(), Ok(()));
+        let tree = open();
+        assert_eq!(tree.get(&[0x01]), None);
+        assert_eq!(tree.scan(&[0x00]).count(), 98);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
     // insert test
     #[test]
     fn test_insert() {
@@ -0,0 +1,367 @@
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+use crate::b_tree::BTree;
+use crate::b_tree::dup::encode;
+
+// a blob is a meta kv at encode(key, []) with val | TAG | gen u64 | size u64 | chunk size u32 |
+// and its data in chunks at encode(key, | gen u64 BE | chunk u64 BE |), one a page;
+// each write of the key gets a new gen, so the old chunks stay readable until the new
+// meta replaces the old one. like duplicate keys, blobs get a tree of their own
+const TAG: &[u8] = b"blob";
+const META_SIZE: usize = 24;
+// chunks written between the commits of a writer
+const CHUNKS_PER_COMMIT: u64 = 256;
+
+struct Meta {
+    gen: u64,
+    size: u64,
+    chunk_size: usize,
+}
+
+fn chunk_key(key: &[u8], gen: u64, idx: u64) -> Vec<u8> {
+    let mut suffix = gen.to_be_bytes().to_vec();
+    suffix.extend_from_slice(&idx.to_be_bytes());
+    encode(key, &suffix)
+}
+
+fn seek_pos(pos: u64, size: u64, from: SeekFrom) -> Result<u64> {
+    let (base, offset) = match from {
+        SeekFrom::Start(n) => return Ok(n),
+        SeekFrom::End(n) => (size, n),
+        SeekFrom::Current(n) => (pos, n),
+    };
+    base.checked_add_signed(offset).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "seek before the start"))
+}
+
+impl BTree {
+    // None if the key holds no blob, or something other than a blob meta
+    fn blob_meta(&self, key: &[u8]) -> Option<Meta> {
+        let val = self.get(&encode(key, &[])).filter(|v| v.len() == META_SIZE && v.starts_with(TAG))?;
+        let val = &val[TAG.len()..];
+        Some(Meta {
+            gen: u64::from_le_bytes(val[0..8].try_into().unwrap()),
+            size: u64::from_le_bytes(val[8..16].try_into().unwrap()),
+            chunk_size: u32::from_le_bytes(val[16..20].try_into().unwrap()) as usize,
+        })
+    }
+
+    // delete the chunks of a gen from chunk `from` on
+    fn delete_chunks(&mut self, key: &[u8], gen: u64, from: u64) {
+        let prefix = encode(key, &gen.to_be_bytes());
+        let chunks: Vec<Vec<u8>> = self.scan(&chunk_key(key, gen, from))
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k)
+            .collect();
+        for chunk in chunks {
+            self.delete(&chunk);
+        }
+    }
+
+    // a writer that replaces the blob under the key once finished; it commits the
+    // tree along the way to keep memory bounded, the key changes only at finish().
+    // a txn holds its pages in memory until the commit, so there must be none open
+    pub fn blob_writer(&mut self, key: &[u8]) -> BlobWriter<'_> {
+        assert!(!self.in_txn);
+        assert_ne!(key.len(), 0);
+        assert!(chunk_key(key, 0, 0).len() <= self.max_key_size());
+        let gen = self.blob_meta(key).map_or(1, |m| m.gen + 1);
+        self.begin();
+        // left by a writer that never finished
+        self.delete_chunks(key, gen, 0);
+        BlobWriter {
+            chunk_size: self.max_val_size(),
+            tree: self,
+            key: key.to_vec(),
+            gen,
+            size: 0,
+            pos: 0,
+            chunk: None,
+            written: 0,
+            finished: false,
+        }
+    }
+
+    pub fn blob_reader(&self, key: &[u8]) -> Option<BlobReader<'_>> {
+        assert_ne!(key.len(), 0);
+        let meta = self.blob_meta(key)?;
+        Some(BlobReader { tree: self, key: key.to_vec(), meta, pos: 0, chunk: None })
+    }
+
+    pub fn delete_blob(&mut self, key: &[u8]) -> bool {
+        assert_ne!(key.len(), 0);
+        let Some(meta) = self.blob_meta(key) else {
+            return false;
+        };
+        let own_txn = !self.in_txn;
+        self.begin();
+        self.delete(&encode(key, &[]));
+        self.delete_chunks(key, meta.gen, 0);
+        if own_txn {
+            self.commit();
+        }
+        true
+    }
+}
+
+pub struct BlobWriter<'a> {
+    tree: &'a mut BTree,
+    key: Vec<u8>,
+    gen: u64,
+    chunk_size: usize,
+    size: u64,
+    pos: u64,
+    // the chunk being written, with its index and whether it changed
+    chunk: Option<(u64, Vec<u8>, bool)>,
+    // chunks stored since the last commit
+    written: u64,
+    finished: bool,
+}
+
+impl BlobWriter<'_> {
+    fn store(&mut self) {
+        if let Some((idx, data, true)) = self.chunk.take() {
+            self.tree.insert(&chunk_key(&self.key, self.gen, idx), &data);
+            self.written += 1;
+            if self.written == CHUNKS_PER_COMMIT {
+                self.written = 0;
+                self.tree.commit();
+                self.tree.begin();
+            }
+        }
+    }
+
+    fn load(&mut self, idx: u64) {
+        if self.chunk.as_ref().is_some_and(|(i, _, _)| *i == idx) {
+            return;
+        }
+        self.store();
+        let data = self.tree.get(&chunk_key(&self.key, self.gen, idx)).unwrap_or_default();
+        self.chunk = Some((idx, data, false));
+    }
+
+    // the blob goes in, in one commit with the meta kv
+    pub fn finish(mut self) {
+        self.store();
+        let old = self.tree.blob_meta(&self.key);
+        let mut meta = TAG.to_vec();
+        meta.extend_from_slice(&self.gen.to_le_bytes());
+        meta.extend_from_slice(&self.size.to_le_bytes());
+        meta.extend_from_slice(&(self.chunk_size as u32).to_le_bytes());
+        self.tree.insert(&encode(&self.key, &[]), &meta);
+        if let Some(old) = old {
+            self.tree.delete_chunks(&self.key, old.gen, 0);
+        }
+        // chunks past the end, left by a seek
+        let end = self.size.div_ceil(self.chunk_size as u64);
+        self.tree.delete_chunks(&self.key, self.gen, end);
+        self.tree.commit();
+        self.finished = true;
+    }
+}
+
+impl Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let cs = self.chunk_size as u64;
+        let (idx, off) = (self.pos / cs, (self.pos % cs) as usize);
+        let n = buf.len().min(self.chunk_size - off);
+        self.load(idx);
+        let (_, data, dirty) = self.chunk.as_mut().unwrap();
+        if data.len() < off + n {
+            data.resize(off + n, 0);
+        }
+        data[off..off + n].copy_from_slice(&buf[..n]);
+        *dirty = true;
+        self.pos += n as u64;
+        self.size = self.size.max(self.pos);
+        Ok(n)
+    }
+
+    // stores the current chunk, nothing is visible before finish()
+    fn flush(&mut self) -> Result<()> {
+        self.store();
+        Ok(())
+    }
+}
+
+impl Seek for BlobWriter<'_> {
+    fn seek(&mut self, from: SeekFrom) -> Result<u64> {
+        self.pos = seek_pos(self.pos, self.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+// an unfinished writer leaves the key as it was, its chunks are deleted
+impl Drop for BlobWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.tree.delete_chunks(&self.key, self.gen, 0);
+            self.tree.commit();
+        }
+    }
+}
+
+// reads the blob as it was when opened, one chunk in memory at a time
+pub struct BlobReader<'a> {
+    tree: &'a BTree,
+    key: Vec<u8>,
+    meta: Meta,
+    pos: u64,
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl BlobReader<'_> {
+    pub fn size(&self) -> u64 {
+        self.meta.size
+    }
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.meta.size {
+            return Ok(0);
+        }
+        let cs = self.meta.chunk_size as u64;
+        let (idx, off) = (self.pos / cs, (self.pos % cs) as usize);
+        if self.chunk.as_ref().is_none_or(|(i, _)| *i != idx) {
+            let data = self.tree.get(&chunk_key(&self.key, self.meta.gen, idx)).unwrap_or_default();
+            self.chunk = Some((idx, data));
+        }
+        let data = &self.chunk.as_ref().unwrap().1;
+        let n = buf.len().min(self.meta.chunk_size - off).min((self.meta.size - self.pos) as usize);
+        // a chunk skipped by a seek reads as zeros
+        for (i, b) in buf[..n].iter_mut().enumerate() {
+            *b = data.get(off + i).copied().unwrap_or(0);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader<'_> {
+    fn seek(&mut self, from: SeekFrom) -> Result<u64> {
+        self.pos = seek_pos(self.pos, self.meta.size, from)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b_tree::tests::{open, open_new, Rng};
+
+    use super::*;
+
+    fn data(seed: u64, n: usize) -> Vec<u8> {
+        let mut rng = Rng(seed);
+        (0..n).map(|_| rng.next() as u8).collect()
+    }
+
+    // size of the blob the writer replaces
+    fn tree_size(writer: &BlobWriter) -> u64 {
+        writer.tree.blob_meta(&writer.key).unwrap().size
+    }
+
+    #[test]
+    fn test_blob() {
+        let mut tree = open_new("test_blob.db");
+        let big = data(1, 3 << 20);
+        let mut writer = tree.blob_writer(b"big");
+        // writes of odd sizes across the chunks
+        for part in big.chunks(7777) {
+            writer.write_all(part).unwrap();
+        }
+        writer.finish();
+        drop(tree);
+
+        let tree = open("test_blob.db");
+        let mut reader = tree.blob_reader(b"big").unwrap();
+        assert_eq!(reader.size(), big.len() as u64);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read == big);
+
+        let mut buf = [0; 100];
+        reader.seek(SeekFrom::Start(1234567)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, big[1234567..1234667]);
+        reader.seek(SeekFrom::End(-50)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 50);
+        assert!(reader.seek(SeekFrom::Current(-(big.len() as i64) - 1)).is_err());
+        assert!(tree.blob_reader(b"none").is_none());
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_blob_replace() {
+        let mut tree = open_new("test_blob_replace.db");
+        let mut writer = tree.blob_writer(b"a");
+        writer.write_all(&data(1, 100000)).unwrap();
+        writer.finish();
+        let chunks = tree.count();
+
+        // seeks patch what is written, and leave zeros in the gaps
+        let small = data(2, 5000);
+        let mut writer = tree.blob_writer(b"a");
+        writer.write_all(&small).unwrap();
+        writer.seek(SeekFrom::Start(10)).unwrap();
+        writer.write_all(b"patch").unwrap();
+        writer.seek(SeekFrom::Start(20000)).unwrap();
+        writer.write_all(b"end").unwrap();
+        // still the old blob until finished
+        assert_eq!(tree_size(&writer), 100000);
+        writer.finish();
+
+        let mut expect = small.clone();
+        expect[10..15].copy_from_slice(b"patch");
+        expect.resize(20000, 0);
+        expect.extend_from_slice(b"end");
+        let mut read = Vec::new();
+        tree.blob_reader(b"a").unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == expect);
+        // the old chunks are gone, the gap takes none
+        assert!(tree.count() < chunks / 2);
+
+        // an unfinished writer changes nothing, and leaves no chunks behind
+        let count = tree.count();
+        let mut writer = tree.blob_writer(b"a");
+        writer.write_all(&data(3, (CHUNKS_PER_COMMIT as usize + 10) * 4000)).unwrap();
+        drop(writer);
+        assert_eq!(tree.blob_reader(b"a").unwrap().size(), 20003);
+        assert_eq!(tree.count(), count);
+        let mut writer = tree.blob_writer(b"a");
+        writer.write_all(b"x").unwrap();
+        writer.finish();
+        assert_eq!(tree.count(), 2);
+
+        assert!(tree.delete_blob(b"a"));
+        assert!(!tree.delete_blob(b"a"));
+        assert_eq!(tree.count(), 0);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_blob_meta() {
+        let mut tree = open_new("test_blob_meta.db");
+        // plain kvs where a blob meta would be are no blobs, whatever their size
+        tree.insert(&encode(b"plain", &[]), b"x");
+        tree.insert(&encode(b"twenty", &[]), &[0x01; 20]);
+        tree.insert(&encode(b"untagged", &[]), &[0x01; META_SIZE]);
+        for key in [&b"plain"[..], b"twenty", b"untagged"] {
+            assert!(tree.blob_reader(key).is_none());
+            assert!(!tree.delete_blob(key));
+        }
+
+        let mut writer = tree.blob_writer(b"twenty");
+        writer.write_all(b"abc").unwrap();
+        writer.finish();
+        assert_eq!(tree.blob_reader(b"twenty").unwrap().size(), 3);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "in_txn")]
+    fn test_blob_in_txn() {
+        let mut tree = open_new("test_blob_in_txn.db");
+        tree.begin();
+        tree.blob_writer(b"a");
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+
+use crate::b_node::BType;
+use crate::b_tree::BTree;
+use crate::b_tree::count::{COUNT_SIZE, KID_VAL_SIZE};
+
+struct Checker<'a> {
+    tree: &'a BTree,
+    used: u64,
+    reachable: HashSet<u64>,
+    leaf_depth: Option<usize>,
+    errs: Vec<String>,
+}
+
+impl BTree {
+    // walk the tree from the root and account for every page,
+    // collecting all violations instead of stopping at the first
+    pub fn check(&self) -> Result<(), Vec<String>> {
+        let mut checker = Checker {
+            tree: self,
+            used: self.persist.used(),
+            reachable: HashSet::new(),
+            leaf_depth: None,
+            errs: Vec::new(),
+        };
+        if self.root != 0 {
+            checker.check_node(self.root, None, None, 0);
+        }
+        checker.check_pages();
+        if checker.errs.is_empty() {
+            Ok(())
+        } else {
+            Err(checker.errs)
+        }
+    }
+}
+
+impl Checker<'_> {
+    // `first` is the separator the parent keeps for this node, `hi` the next one;
+    // returns the keys under it
+    fn check_node(&mut self, ptr: u64, first: Option<&[u8]>, hi: Option<&[u8]>, depth: usize) -> u64 {
+        if ptr == 0 || ptr >= self.used {
+            self.errs.push(format!("page {}: pointer beyond used count {}", ptr, self.used));
+            return 0;
+        }
+        if !self.reachable.insert(ptr) {
+            self.errs.push(format!("page {}: referenced more than once", ptr));
+            return 0;
+        }
+        let node = self.tree.persist.get_node(ptr);
+        if let Err(e) = node.check_layout() {
+            self.errs.push(format!("page {}: {}", ptr, e));
+            return 0;
+        }
+        if node.n_bytes() as usize > self.tree.page_size {
+            self.errs.push(format!("page {}: {} bytes over page size", ptr, node.n_bytes()));
+        }
+        let n_keys = node.n_keys();
+        if n_keys == 0 {
+            self.errs.push(format!("page {}: empty node", ptr));
+            return 0;
+        }
+
+        // keys
+        for i in 1..n_keys {
+            if node.get_key(i - 1) >= node.get_key(i) {
+                self.errs.push(format!("page {}: key {} not above key {}", ptr, i, i - 1));
+            }
+        }
+        match first {
+            Some(first) if node.get_key(0) != first => {
+                self.errs.push(format!("page {}: first key differs from parent separator", ptr));
+            }
+            None if !node.get_key(0).is_empty() => {
+                self.errs.push(format!("page {}: root does not start with the empty key", ptr));
+            }
+            _ => {}
+        }
+        if let Some(hi) = hi {
+            if node.get_key(n_keys - 1) >= hi {
+                self.errs.push(format!("page {}: keys reach the next separator", ptr));
+            }
+        }
+
+        // kids
+        match node.n_type() {
+            BType::LEAF => {
+                match self.leaf_depth {
+                    None => self.leaf_depth = Some(depth),
+                    Some(d) if d != depth => {
+                        self.errs.push(format!("page {}: leaf at depth {}, expected {}", ptr, depth, d));
+                    }
+                    _ => {}
+                }
+                n_keys as u64
+            }
+            BType::Node => {
+                let mut total = 0;
+                for i in 0..n_keys {
+                    let next = if i + 1 < n_keys { Some(node.get_key(i + 1)) } else { hi };
+                    let errs = self.errs.len();
+                    let count = self.check_node(node.get_ptr(i), Some(node.get_key(i)), next, depth + 1);
+                    // entries from before the counts have none, from before the hashes no hash
+                    let val = node.get_val(i);
+                    match val.len() {
+                        0 => {}
+                        COUNT_SIZE | KID_VAL_SIZE if u64::from_le_bytes(val[..COUNT_SIZE].try_into().unwrap()) == count => {}
+                        _ => self.errs.push(format!("page {}: kid {} does not hold {} keys", ptr, i, count)),
+                    }
+                    // a kid with problems of its own may not hash at all
+                    if val.len() == KID_VAL_SIZE && self.errs.len() == errs
+                        && val[COUNT_SIZE..] != self.tree.node_hash(&self.tree.persist.get_node(node.get_ptr(i))) {
+                        self.errs.push(format!("page {}: kid {} hash does not match", ptr, i));
+                    }
+                    total += count;
+                }
+                total
+            }
+        }
+    }
+
+    fn check_pages(&mut self) {
+        let mut free = HashSet::new();
+        for ptr in self.tree.persist.free_pages() {
+            if ptr == 0 || ptr >= self.used {
+                self.errs.push(format!("page {}: free page beyond used count {}", ptr, self.used));
+            } else if self.reachable.contains(&ptr) {
+                self.errs.push(format!("page {}: reachable but on the free list", ptr));
+            }
+            if !free.insert(ptr) {
+                self.errs.push(format!("page {}: on the free list twice", ptr));
+            }
+        }
+        for ptr in 1..self.used {
+            if !self.reachable.contains(&ptr) && !free.contains(&ptr) {
+                self.errs.push(format!("page {}: leaked", ptr));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use crate::b_node::BNode;
+    use crate::b_tree::tests::MockPersist;
+    use crate::common::BTREE_PAGE_SIZE;
+    use crate::kv::KV;
+
+    use super::*;
+
+    fn leaf(keys: &[&[u8]]) -> BNode {
+        let mut node = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        node.set_header(BType::LEAF, keys.len() as u16);
+        for (i, key) in keys.iter().enumerate() {
+            node.insert_kv(i as u16, 0, key, &[0xac]);
+        }
+        node
+    }
+
+    fn root(kids: &[(u64, &[u8])]) -> BNode {
+        let mut node = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        node.set_header(BType::Node, kids.len() as u16);
+        for (i, (ptr, key)) in kids.iter().enumerate() {
+            node.insert_kv(i as u16, *ptr, key, &[]);
+        }
+        node
+    }
+
+    fn fill(tree: &mut BTree) {
+        for i in 0..300u32 {
+            tree.insert(&(i * 7919 % 300).to_be_bytes(), &[0xca; 100]);
+        }
+        for i in (0..300u32).step_by(3) {
+            tree.delete(&i.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_check_mock() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        assert_eq!(tree.check(), Ok(()));
+        fill(&mut tree);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_check_kv() {
+        let _ = remove_file("test_check_kv.db");
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_check_kv.db")).unwrap()));
+        fill(&mut tree);
+        assert_eq!(tree.check(), Ok(()));
+        drop(tree);
+
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_check_kv.db")).unwrap()));
+        assert_eq!(tree.check(), Ok(()));
+        for i in 0..300u32 {
+            tree.delete(&i.to_be_bytes());
+        }
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_check_order() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        let left = tree.persist.new_node(&leaf(&[b"", b"x"]));
+        let right = tree.persist.new_node(&leaf(&[b"m", b"c"]));
+        tree.root = tree.persist.new_node(&root(&[(left, b""), (right, b"n")]));
+        let errs = tree.check().unwrap_err();
+        assert_eq!(errs, vec![
+            format!("page {}: keys reach the next separator", left),
+            format!("page {}: key 1 not above key 0", right),
+            format!("page {}: first key differs from parent separator", right),
+        ]);
+    }
+
+    #[test]
+    fn test_check_depth() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        let a = tree.persist.new_node(&leaf(&[b""]));
+        let b = tree.persist.new_node(&leaf(&[b"m"]));
+        let inner = tree.persist.new_node(&root(&[(b, b"m")]));
+        tree.root = tree.persist.new_node(&root(&[(a, b""), (inner, b"m"), (a, b"z")]));
+        let errs = tree.check().unwrap_err();
+        assert!(errs.contains(&format!("page {}: leaf at depth 2, expected 1", b)));
+        assert!(errs.contains(&format!("page {}: referenced more than once", a)));
+    }
+
+    #[test]
+    fn test_check_pages() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        fill(&mut tree);
+        let leak = tree.persist.new_node(&leaf(&[b""]));
+        let errs = tree.check().unwrap_err();
+        assert_eq!(errs, vec![format!("page {}: leaked", leak)]);
+
+        let bad = tree.persist.new_node(&root(&[(leak, b""), (leak + 10, b"z")]));
+        tree.root = bad;
+        let errs = tree.check().unwrap_err();
+        assert!(errs.contains(&format!("page {}: pointer beyond used count {}", leak + 10, bad + 1)));
+    }
+}
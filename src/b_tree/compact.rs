@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use crate::b_node::BType;
+use crate::b_tree::BTree;
+use crate::common::Persist;
+use crate::kv::{KV, Storage};
+
+// pages written to the new file between flushes
+const COMPACT_BATCH: usize = 1024;
+
+impl BTree {
+    // rewrite the live tree into a new db file, level by level from the leaves,
+    // so the leaves end up at the front of the file in key order
+    pub fn compact(&self, dest_path: &str) -> Result<(), String> {
+        let mut dest = KV::open_with_page_size(String::from(dest_path), Storage::Mmap, self.page_size)?;
+        if dest.used() != 1 {
+            return Err(format!("{} is not an empty db", dest_path));
+        }
+        let root = self.copy_to(&mut dest);
+        dest.set_root(root);
+        dest.flush();
+        Ok(())
+    }
+
+    fn copy_to(&self, dest: &mut dyn Persist) -> u64 {
+        if self.root == 0 {
+            return 0;
+        }
+        let mut levels = vec![vec![self.root]];
+        loop {
+            let level = levels.last().unwrap();
+            if self.persist.get_node(level[0]).n_type() == BType::LEAF {
+                break;
+            }
+            let mut kids = Vec::new();
+            for ptr in level {
+                let node = self.persist.get_node(*ptr);
+                kids.extend((0..node.n_keys()).map(|i| node.get_ptr(i)));
+            }
+            levels.push(kids);
+        }
+
+        let mut moved = HashMap::new();
+        for level in levels.iter().rev() {
+            for ptr in level {
+                let mut node = self.persist.get_node(*ptr);
+                if node.n_type() == BType::Node {
+                    for i in 0..node.n_keys() {
+                        node.set_ptr(i, moved[&node.get_ptr(i)]);
+                    }
+                }
+                moved.insert(*ptr, dest.new_node(&node));
+                // keep the unflushed pages bounded, the root is set at the end
+                if moved.len() % COMPACT_BATCH == 0 {
+                    dest.flush();
+                }
+            }
+        }
+        moved[&self.root]
+    }
+
+    // move the pages past the end of the live tree into free pages below it,
+    // then cut the file; each pass is a commit of its own
+    pub fn compact_in_place(&mut self) {
+        assert!(!self.in_txn);
+        let mut last = usize::MAX;
+        loop {
+            let end = self.persist.used() - self.persist.free_pages().len() as u64;
+            let mut high = 0;
+            if self.root != 0 {
+                if let Some(root) = self.relocate(self.root, end, &mut high) {
+                    self.root = root;
+                }
+            }
+            self.flush();
+            if high == 0 || high >= last {
+                break;
+            }
+            last = high;
+        }
+        self.persist.truncate();
+    }
+
+    // copy the subtree if it or a kid lives at `end` or past it,
+    // new pages come from the lowest free ones
+    fn relocate(&mut self, ptr: u64, end: u64, high: &mut usize) -> Option<u64> {
+        let mut node = self.persist.get_node(ptr);
+        let mut changed = ptr >= end;
+        if changed {
+            *high += 1;
+        }
+        if node.n_type() == BType::Node {
+            for i in 0..node.n_keys() {
+                if let Some(kid) = self.relocate(node.get_ptr(i), end, high) {
+                    node.set_ptr(i, kid);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return None;
+        }
+        self.del_node(ptr);
+        Some(self.persist.new_node(&node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::{metadata, remove_file};
+
+    use crate::b_tree::tests::{fill, open, open_new};
+    use crate::common::BTREE_PAGE_SIZE;
+
+    use super::*;
+
+    // a big tree with most of it deleted again, spread over the key space
+    fn churn(tree: &mut BTree) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut model = fill(tree, 3000, |i| vec![i as u8; 200]);
+        // a commit per hundred, so the freed pages spread out
+        for i in 0..3000u32 {
+            if i % 10 != 0 {
+                if i % 100 == 1 {
+                    tree.begin();
+                }
+                tree.delete(&i.to_be_bytes());
+                model.remove(i.to_be_bytes().as_slice());
+                if i % 100 == 99 {
+                    tree.commit();
+                }
+            }
+        }
+        model
+    }
+
+    fn file_pages(path: &str) -> u64 {
+        metadata(path).unwrap().len() / BTREE_PAGE_SIZE as u64
+    }
+
+    #[test]
+    fn test_compact() {
+        let _ = remove_file("test_compact_src.db");
+        let _ = remove_file("test_compact_dest.db");
+        let mut tree = open("test_compact_src.db");
+        let model = churn(&mut tree);
+        tree.compact("test_compact_dest.db").unwrap();
+        assert!(tree.compact("test_compact_dest.db").is_err());
+
+        let dest = open("test_compact_dest.db");
+        assert_eq!(dest.check(), Ok(()));
+        assert!(dest.scan(&[0x00]).eq(model.into_iter()));
+        // nothing free, the leaves come first
+        assert!(dest.persist.free_pages().is_empty());
+        assert_eq!(dest.persist.used(), file_pages("test_compact_dest.db"));
+        assert!(file_pages("test_compact_dest.db") * 4 < file_pages("test_compact_src.db"));
+        let mut ptr = dest.root;
+        while dest.persist.get_node(ptr).n_type() == BType::Node {
+            ptr = dest.persist.get_node(ptr).get_ptr(0);
+        }
+        assert_eq!(ptr, 1);
+    }
+
+    #[test]
+    fn test_compact_empty() {
+        let _ = remove_file("test_compact_empty_src.db");
+        let _ = remove_file("test_compact_empty_dest.db");
+        let tree = open("test_compact_empty_src.db");
+        tree.compact("test_compact_empty_dest.db").unwrap();
+        assert_eq!(open("test_compact_empty_dest.db").scan(&[0x00]).count(), 0);
+    }
+
+    #[test]
+    fn test_compact_in_place() {
+        let mut tree = open_new("test_compact_in_place.db");
+        let model = churn(&mut tree);
+        let before = file_pages("test_compact_in_place.db");
+        tree.compact_in_place();
+        assert_eq!(tree.check(), Ok(()));
+        let after = file_pages("test_compact_in_place.db");
+        assert!(after * 4 < before, "{} -> {}", before, after);
+        assert_eq!(tree.persist.used(), after);
+        drop(tree);
+
+        let mut tree = open("test_compact_in_place.db");
+        assert_eq!(tree.check(), Ok(()));
+        assert!(tree.scan(&[0x00]).eq(model.into_iter()));
+        // still usable
+        tree.insert(b"k", b"v");
+        assert_eq!(tree.check(), Ok(()));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::b_node::{BNode, BType};
+use crate::b_tree::BTree;
+
+// in augmented trees inner node entries carry | count u64 | hash | in their val:
+// the number of keys under the kid, counting the empty sentinel key, and the merkle
+// hash of the kid. entries of plain trees have an empty val, the kid is counted by
+// walking it and hashed when needed; entries written before the hashes have only
+// the count
+pub const COUNT_SIZE: usize = 8;
+pub const KID_VAL_SIZE: usize = COUNT_SIZE + 32;
+
+impl BTree {
+    // keys under a node, the sentinel included
+    pub(crate) fn subtree_count(&self, node: &BNode) -> u64 {
+        match node.n_type() {
+            BType::LEAF => node.n_keys() as u64,
+            BType::Node => (0..node.n_keys()).map(|i| self.kid_count(node, i)).sum(),
+        }
+    }
+
+    fn kid_count(&self, node: &BNode, idx: u16) -> u64 {
+        match node.get_val(idx).get(..COUNT_SIZE) {
+            Some(count) => u64::from_le_bytes(count.try_into().unwrap()),
+            None => self.subtree_count(&self.persist.get_node(node.get_ptr(idx))),
+        }
+    }
+
+    // the val of the entry for a kid in its parent
+    pub(crate) fn kid_val(&self, kid: &BNode) -> Vec<u8> {
+        if !self.augmented {
+            return Vec::new();
+        }
+        let mut val = self.subtree_count(kid).to_le_bytes().to_vec();
+        val.extend_from_slice(&self.node_hash(kid));
+        val
+    }
+
+    pub fn count(&self) -> u64 {
+        if self.root == 0 {
+            return 0;
+        }
+        self.subtree_count(&self.persist.get_node(self.root)) - 1
+    }
+
+    // keys below `key`
+    pub fn rank(&self, key: &[u8]) -> u64 {
+        assert_ne!(key.len(), 0);
+        if self.root == 0 {
+            return 0;
+        }
+        let mut rank = 0;
+        let mut node = self.persist.get_node(self.root);
+        loop {
+            let idx = node.lookup_le(key);
+            match node.n_type() {
+                BType::Node => {
+                    rank += (0..idx).map(|i| self.kid_count(&node, i)).sum::<u64>();
+                    node = self.persist.get_node(node.get_ptr(idx));
+                }
+                BType::LEAF => {
+                    rank += (0..node.n_keys()).filter(|i| node.get_key(*i) < key).count() as u64;
+                    // not the sentinel
+                    return rank - 1;
+                }
+            }
+        }
+    }
+
+    // keys in [start, end)
+    pub fn count_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        self.rank(end).saturating_sub(self.rank(start))
+    }
+
+    // the kv at position `n` in key order, from 0
+    pub fn nth(&self, n: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+        if n >= self.count() {
+            return None;
+        }
+        // past the sentinel
+        let mut n = n + 1;
+        let mut node = self.persist.get_node(self.root);
+        loop {
+            match node.n_type() {
+                BType::Node => {
+                    let mut idx = 0;
+                    loop {
+                        let count = self.kid_count(&node, idx);
+                        if n < count {
+                            break;
+                        }
+                        n -= count;
+                        idx += 1;
+                    }
+                    node = self.persist.get_node(node.get_ptr(idx));
+                }
+                BType::LEAF => {
+                    let idx = n as u16;
+                    return Some((node.get_key(idx).to_vec(), node.get_val(idx).to_vec()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::remove_file;
+
+    use crate::b_tree::tests::MockPersist;
+    use crate::common::BTREE_PAGE_SIZE;
+    use crate::kv::KV;
+
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        for augmented in [false, true] {
+            count_tree(augmented);
+        }
+    }
+
+    fn count_tree(augmented: bool) {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        tree.set_augmented(augmented);
+        assert_eq!(tree.count(), 0);
+        assert_eq!(tree.nth(0), None);
+        let mut model = BTreeMap::new();
+        for i in 0..3000u32 {
+            let key = (i * 7919 % 3000 * 2).to_be_bytes().to_vec();
+            tree.insert(&key, &[i as u8; 100]);
+            model.insert(key, vec![i as u8; 100]);
+        }
+        for i in (0..6000u32).step_by(7) {
+            tree.delete(&i.to_be_bytes());
+            model.remove(i.to_be_bytes().as_slice());
+        }
+        assert_eq!(tree.check(), Ok(()));
+        assert_eq!(tree.count(), model.len() as u64);
+        // a plain tree keeps the entries empty
+        let root = tree.persist.get_node(tree.root);
+        assert_eq!(root.get_val(0).len(), if augmented { KID_VAL_SIZE } else { 0 });
+
+        let keys: Vec<&Vec<u8>> = model.keys().collect();
+        for n in (0..keys.len()).step_by(37) {
+            let (key, val) = tree.nth(n as u64).unwrap();
+            assert_eq!(&key, keys[n]);
+            assert_eq!(val, model[&key]);
+            assert_eq!(tree.rank(&key), n as u64);
+        }
+        assert_eq!(tree.nth(model.len() as u64), None);
+        // keys between and around the stored ones
+        assert_eq!(tree.rank(&[0x00]), 0);
+        assert_eq!(tree.rank(&[0xff; 5]), model.len() as u64);
+        let (start, end) = (1001u32.to_be_bytes(), 2001u32.to_be_bytes());
+        let n = model.range(start.to_vec()..end.to_vec()).count() as u64;
+        assert_eq!(tree.count_range(&start, &end), n);
+        assert_eq!(tree.count_range(&end, &start), 0);
+    }
+
+    #[test]
+    fn test_count_kv() {
+        let _ = remove_file("test_count_kv.db");
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_count_kv.db")).unwrap()));
+        tree.set_augmented(true);
+        for i in 0..2000u32 {
+            tree.insert(&i.to_be_bytes(), &[0xac; 200]);
+        }
+        drop(tree);
+
+        let tree = BTree::new(Box::new(KV::new(String::from("test_count_kv.db")).unwrap()));
+        assert_eq!(tree.count(), 2000);
+        assert_eq!(tree.nth(1234).unwrap().0, 1234u32.to_be_bytes());
+        assert_eq!(tree.count_range(&100u32.to_be_bytes(), &200u32.to_be_bytes()), 100);
+    }
+
+    #[test]
+    fn test_count_without_counts() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        let mut kids = Vec::new();
+        for keys in [&[&b""[..], b"a", b"b"][..], &[b"m", b"n"]] {
+            let mut leaf = BNode::new_with_cap(BTREE_PAGE_SIZE);
+            leaf.set_header(BType::LEAF, keys.len() as u16);
+            for (i, key) in keys.iter().enumerate() {
+                leaf.insert_kv(i as u16, 0, key, &[0xac]);
+            }
+            kids.push((tree.persist.new_node(&leaf), keys[0]));
+        }
+        // an inner node as written before the counts
+        let mut root = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        root.set_header(BType::Node, 2);
+        for (i, (ptr, key)) in kids.iter().enumerate() {
+            root.insert_kv(i as u16, *ptr, key, &[]);
+        }
+        tree.root = tree.persist.new_node(&root);
+        tree.set_augmented(true);
+        assert_eq!(tree.check(), Ok(()));
+        assert_eq!(tree.count(), 4);
+        assert_eq!(tree.rank(b"m"), 2);
+        assert_eq!(tree.nth(3).unwrap().0, b"n");
+
+        tree.insert(b"c", &[0xac]);
+        assert_eq!(tree.check(), Ok(()));
+        assert_eq!(tree.persist.get_node(tree.root).get_val(0)[..COUNT_SIZE], 4u64.to_le_bytes());
+        assert_eq!(tree.nth(3).unwrap().0, b"m");
+    }
+}
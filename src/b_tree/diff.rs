@@ -0,0 +1,330 @@
+use std::cmp::Ordering;
+
+use crate::b_node::BType;
+use crate::b_tree::BTree;
+use crate::b_tree::merkle::Hash;
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    // key, val
+    Added(Vec<u8>, Vec<u8>),
+    Removed(Vec<u8>, Vec<u8>),
+    // key, old val, new val
+    Changed(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+// what is left to walk of one tree, the smallest key last
+pub(crate) enum Item {
+    // subtree with its first key, height above the leaves and, to compare it
+    // with a subtree of another persist, its hash
+    Node(u64, Vec<u8>, usize, Option<Hash>),
+    Kv(Vec<u8>, Vec<u8>),
+}
+
+// where the kids of a subtree come from
+pub(crate) trait Expand {
+    // replace the subtree on top of the stack by its kids
+    fn expand(&mut self, side: &mut Vec<Item>) -> Result<(), String>;
+}
+
+// a tree read through its own persist, with kid hashes when compared across persists
+pub(crate) struct Local<'a> {
+    pub(crate) tree: &'a BTree,
+    pub(crate) hashed: bool,
+}
+
+impl Local<'_> {
+    pub(crate) fn start(&self, root: u64) -> Vec<Item> {
+        if root == 0 {
+            return Vec::new();
+        }
+        let hash = self.hashed.then(|| self.tree.node_hash(&self.tree.persist.get_node(root)));
+        // the leftmost leaf starts with the empty key
+        vec![Item::Node(root, Vec::new(), self.tree.height(root), hash)]
+    }
+}
+
+impl Expand for Local<'_> {
+    fn expand(&mut self, side: &mut Vec<Item>) -> Result<(), String> {
+        let Some(Item::Node(ptr, _, height, _)) = side.pop() else {
+            unreachable!()
+        };
+        let node = self.tree.persist.get_node(ptr);
+        for i in (0..node.n_keys()).rev() {
+            let key = node.get_key(i).to_vec();
+            side.push(match node.n_type() {
+                BType::Node => {
+                    let hash = self.hashed.then(|| self.tree.kid_hash(&node, i));
+                    Item::Node(node.get_ptr(i), key, height - 1, hash)
+                }
+                BType::LEAF => Item::Kv(key, node.get_val(i).to_vec()),
+            });
+        }
+        Ok(())
+    }
+}
+
+// the changes from one tree to another in key order; subtrees both share are
+// skipped, by hash when both have one, by pointer otherwise
+pub(crate) struct Walk<A, B> {
+    a: A,
+    b: B,
+    old: Vec<Item>,
+    new: Vec<Item>,
+}
+
+impl<A: Expand, B: Expand> Walk<A, B> {
+    pub(crate) fn new(a: A, old: Vec<Item>, b: B, new: Vec<Item>) -> Self {
+        Walk { a, b, old, new }
+    }
+
+    pub(crate) fn next_change(&mut self) -> Result<Option<Change>, String> {
+        let (old, new) = (&mut self.old, &mut self.new);
+        loop {
+            let change = match (old.last(), new.last()) {
+                (None, None) => return Ok(None),
+                (Some(Item::Node(..)), None) => {
+                    self.a.expand(old)?;
+                    continue;
+                }
+                (None, Some(Item::Node(..))) => {
+                    self.b.expand(new)?;
+                    continue;
+                }
+                (Some(Item::Kv(..)), None) => {
+                    let Some(Item::Kv(k, v)) = old.pop() else { unreachable!() };
+                    Change::Removed(k, v)
+                }
+                (None, Some(Item::Kv(..))) => {
+                    let Some(Item::Kv(k, v)) = new.pop() else { unreachable!() };
+                    Change::Added(k, v)
+                }
+                (Some(Item::Node(a, ka, ha, x)), Some(Item::Node(b, kb, hb, y))) => {
+                    let same = match (x, y) {
+                        (Some(x), Some(y)) => x == y,
+                        _ => a == b,
+                    };
+                    if same {
+                        old.pop();
+                        new.pop();
+                        continue;
+                    }
+                    // the taller one first when they start together, its kids may be shared
+                    match ka.cmp(kb).then(hb.cmp(ha)) {
+                        Ordering::Less => self.a.expand(old)?,
+                        Ordering::Greater => self.b.expand(new)?,
+                        Ordering::Equal => {
+                            self.a.expand(old)?;
+                            self.b.expand(new)?;
+                        }
+                    }
+                    continue;
+                }
+                (Some(Item::Node(_, k, _, _)), Some(Item::Kv(key, _))) => {
+                    if k <= key {
+                        self.a.expand(old)?;
+                        continue;
+                    }
+                    let Some(Item::Kv(k, v)) = new.pop() else { unreachable!() };
+                    Change::Added(k, v)
+                }
+                (Some(Item::Kv(key, _)), Some(Item::Node(_, k, _, _))) => {
+                    if k <= key {
+                        self.b.expand(new)?;
+                        continue;
+                    }
+                    let Some(Item::Kv(k, v)) = old.pop() else { unreachable!() };
+                    Change::Removed(k, v)
+                }
+                (Some(Item::Kv(ka, _)), Some(Item::Kv(kb, _))) => match ka.cmp(kb) {
+                    Ordering::Less => {
+                        let Some(Item::Kv(k, v)) = old.pop() else { unreachable!() };
+                        Change::Removed(k, v)
+                    }
+                    Ordering::Greater => {
+                        let Some(Item::Kv(k, v)) = new.pop() else { unreachable!() };
+                        Change::Added(k, v)
+                    }
+                    Ordering::Equal => {
+                        let (Some(Item::Kv(k, a)), Some(Item::Kv(_, b))) = (old.pop(), new.pop()) else { unreachable!() };
+                        if a == b {
+                            continue;
+                        }
+                        Change::Changed(k, a, b)
+                    }
+                },
+            };
+            // the empty sentinel key is in every tree
+            match &change {
+                Change::Added(k, _) | Change::Removed(k, _) if k.is_empty() => continue,
+                _ => return Ok(Some(change)),
+            }
+        }
+    }
+}
+
+// the changes from one root to another of the same persist, only the paths
+// that differ are read
+pub struct Diff<'a> {
+    walk: Walk<Local<'a>, Local<'a>>,
+}
+
+impl BTree {
+    // roots of the same persist, from versions() or root()
+    pub fn diff(&self, old: u64, new: u64) -> Diff<'_> {
+        let local = || Local { tree: self, hashed: false };
+        Diff {
+            walk: Walk::new(local(), local().start(old), local(), local().start(new)),
+        }
+    }
+
+    // levels of inner nodes above the leaves
+    pub(crate) fn height(&self, root: u64) -> usize {
+        if root == 0 {
+            return 0;
+        }
+        let mut height = 0;
+        let mut node = self.persist.get_node(root);
+        while node.n_type() == BType::Node {
+            node = self.persist.get_node(node.get_ptr(0));
+            height += 1;
+        }
+        height
+    }
+}
+
+impl Iterator for Diff<'_> {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // reading a local tree does not fail
+        self.walk.next_change().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::fs::remove_file;
+    use std::rc::Rc;
+
+    use crate::b_node::BNode;
+    use crate::b_tree::tests::fill;
+    use crate::common::Persist;
+    use crate::kv::KV;
+
+    use super::*;
+
+    // counts the nodes read
+    struct Counting {
+        inner: KV,
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl Persist for Counting {
+        fn get_node(&self, ptr: u64) -> BNode {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.get_node(ptr)
+        }
+        fn new_node(&mut self, node: &BNode) -> u64 {
+            self.inner.new_node(node)
+        }
+        fn del_node(&mut self, ptr: u64) {
+            self.inner.del_node(ptr)
+        }
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+        fn used(&self) -> u64 {
+            self.inner.used()
+        }
+        fn free_pages(&self) -> Vec<u64> {
+            self.inner.free_pages()
+        }
+        fn get_root(&self) -> u64 {
+            self.inner.get_root()
+        }
+        fn set_root(&mut self, root: u64) {
+            self.inner.set_root(root)
+        }
+        fn flush(&mut self) {
+            self.inner.flush()
+        }
+        fn set_retain(&mut self, retain: usize) -> Result<(), String> {
+            self.inner.set_retain(retain)
+        }
+    }
+
+    fn open(path: &str) -> (BTree, Rc<Cell<usize>>) {
+        let _ = remove_file(path);
+        let reads = Rc::new(Cell::new(0));
+        let inner = KV::new(String::from(path)).unwrap();
+        let mut tree = BTree::new(Box::new(Counting { inner, reads: reads.clone() }));
+        tree.retain(2).unwrap();
+        (tree, reads)
+    }
+
+    // the diff of two models, the way the tree should report it
+    fn model_diff(old: &BTreeMap<Vec<u8>, Vec<u8>>, new: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<Change> {
+        let mut keys: Vec<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter().filter_map(|k| match (old.get(k), new.get(k)) {
+            (Some(a), None) => Some(Change::Removed(k.clone(), a.clone())),
+            (None, Some(b)) => Some(Change::Added(k.clone(), b.clone())),
+            (Some(a), Some(b)) if a != b => Some(Change::Changed(k.clone(), a.clone(), b.clone())),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let (mut tree, reads) = open("test_diff.db");
+        let mut model = fill(&mut tree, 3000, |_| vec![1; 50]);
+        let (old_root, old) = (tree.root(), model.clone());
+
+        tree.begin();
+        tree.insert(&5u32.to_be_bytes(), &[2; 50]);
+        tree.delete(&1000u32.to_be_bytes());
+        tree.insert(&[0xff; 8], &[3; 10]);
+        tree.commit();
+        model.insert(5u32.to_be_bytes().to_vec(), vec![2; 50]);
+        model.remove(1000u32.to_be_bytes().as_slice());
+        model.insert(vec![0xff; 8], vec![3; 10]);
+
+        reads.set(0);
+        let changes: Vec<Change> = tree.diff(old_root, tree.root()).collect();
+        assert_eq!(changes, model_diff(&old, &model));
+        assert_eq!(changes.len(), 3);
+        // the paths to three leaves, not the whole tree
+        let n = reads.replace(0);
+        assert_eq!(tree.diff(0, tree.root()).count(), model.len());
+        assert!(n * 4 < reads.get(), "{} of {} reads", n, reads.get());
+
+        let reversed: Vec<Change> = tree.diff(tree.root(), old_root).collect();
+        assert_eq!(reversed, model_diff(&model, &old));
+        assert_eq!(tree.diff(old_root, old_root).count(), 0);
+    }
+
+    #[test]
+    fn test_diff_heights() {
+        let (mut tree, _) = open("test_diff_heights.db");
+        assert_eq!(tree.diff(0, 0).count(), 0);
+        tree.insert(&[0x01], &[0x01]);
+        let (small_root, small) = (tree.root(), BTreeMap::from([(vec![0x01], vec![0x01])]));
+
+        // one more level, the old leaf is not shared
+        let mut model = small.clone();
+        tree.begin();
+        for i in 0..2000u32 {
+            tree.insert(&i.to_be_bytes(), &[0xac; 100]);
+            model.insert(i.to_be_bytes().to_vec(), vec![0xac; 100]);
+        }
+        tree.commit();
+        let changes: Vec<Change> = tree.diff(small_root, tree.root()).collect();
+        assert_eq!(changes, model_diff(&small, &model));
+        let changes: Vec<Change> = tree.diff(tree.root(), 0).collect();
+        assert_eq!(changes, model_diff(&model, &BTreeMap::new()));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::b_tree::BTree;
+
+// duplicate keys are kept as distinct tree keys | key | 0x00 0x01 | val | with empty
+// vals, a 0x00 in the key is stored as 0x00 0xff; the escape keeps the tree order the
+// order of (key, val), so the vals of a key are next to each other and sorted.
+// keys written by insert() that do not decode are skipped by scan_dup()
+const ESCAPE: u8 = 0xff;
+const END: u8 = 0x01;
+
+pub(crate) fn encode(key: &[u8], val: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(key.len() + val.len() + 2);
+    for b in key {
+        data.push(*b);
+        if *b == 0 {
+            data.push(ESCAPE);
+        }
+    }
+    data.extend_from_slice(&[0, END]);
+    data.extend_from_slice(val);
+    data
+}
+
+// None for keys not written by insert_dup()
+fn decode(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut key = Vec::new();
+    let mut i = 0;
+    loop {
+        let b = *data.get(i)?;
+        match (b, *data.get(i + 1)?) {
+            (0, END) => return Some((key, data[i + 2..].to_vec())),
+            // past the escape of a 0x00
+            (0, ESCAPE) => i += 2,
+            (0, _) => return None,
+            _ => i += 1,
+        }
+        key.push(b);
+    }
+}
+
+impl BTree {
+    // the key and val together have to fit a key
+    pub fn insert_dup(&mut self, key: &[u8], val: &[u8]) {
+        assert_ne!(key.len(), 0);
+        self.insert(&encode(key, val), &[]);
+    }
+
+    // the vals of the key, sorted
+    pub fn get_all(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        assert_ne!(key.len(), 0);
+        let prefix = encode(key, &[]);
+        self.scan(&prefix)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k[prefix.len()..].to_vec())
+            .collect()
+    }
+
+    pub fn delete_one(&mut self, key: &[u8], val: &[u8]) -> bool {
+        assert_ne!(key.len(), 0);
+        self.delete(&encode(key, val))
+    }
+
+    // the (key, val) pairs with key >= start, by key and then val
+    pub fn scan_dup(&self, start: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.scan(&encode(start, &[])).filter(|(_, v)| v.is_empty()).filter_map(|(k, _)| decode(&k))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b_tree::tests::MockPersist;
+
+    use super::*;
+
+    #[test]
+    fn test_dup_encode() {
+        for (key, val) in [(&b"a"[..], &b""[..]), (b"\x00", b"\x00"), (b"a\x00\xffb\x00", b"\x00\x01")] {
+            assert_eq!(decode(&encode(key, val)), Some((key.to_vec(), val.to_vec())));
+        }
+        for data in [&b"a"[..], b"a\x00", b"a\x00\x02b"] {
+            assert_eq!(decode(data), None);
+        }
+        // a key sorts before the keys it is a prefix of
+        assert!(encode(b"a", b"\xff") < encode(b"a\x00", b""));
+        assert!(encode(b"a\x00", b"\xff") < encode(b"a\x01", b""));
+        assert!(encode(b"a", b"\xff") < encode(b"ab", b""));
+    }
+
+    #[test]
+    fn test_dup() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        for i in 0..1000u32 {
+            tree.insert_dup(&(i % 10).to_be_bytes(), &(i * 7919 % 1000).to_be_bytes());
+        }
+        tree.insert_dup(b"a", b"2");
+        tree.insert_dup(b"a", b"1");
+        tree.insert_dup(b"a\x00", b"1");
+        // the same pair once
+        tree.insert_dup(b"a", b"1");
+        assert_eq!(tree.check(), Ok(()));
+
+        assert_eq!(tree.get_all(b"a"), vec![b"1".to_vec(), b"2".to_vec()]);
+        let vals = tree.get_all(&3u32.to_be_bytes());
+        assert_eq!(vals.len(), 100);
+        assert!(vals.windows(2).all(|w| w[0] < w[1]));
+        assert!(tree.get_all(b"b").is_empty());
+
+        assert!(tree.delete_one(b"a", b"2"));
+        assert!(!tree.delete_one(b"a", b"2"));
+        assert_eq!(tree.get_all(b"a"), vec![b"1".to_vec()]);
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.scan_dup(&9u32.to_be_bytes()).collect();
+        assert_eq!(pairs.len(), 102);
+        assert_eq!(pairs[100], (b"a".to_vec(), b"1".to_vec()));
+        assert_eq!(pairs[101], (b"a\x00".to_vec(), b"1".to_vec()));
+
+        // plain keys in the same tree are skipped
+        tree.insert(b"b", b"1");
+        tree.insert(b"c\x00\x02", &[]);
+        tree.insert(&encode(b"d", b"1"), b"1");
+        tree.insert_dup(b"e", b"1");
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = tree.scan_dup(b"a\x00").collect();
+        assert_eq!(pairs, vec![(b"a\x00".to_vec(), b"1".to_vec()), (b"e".to_vec(), b"1".to_vec())]);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::b_node::{BNode, BType};
+use crate::b_tree::BTree;
+
+pub struct Scan<'a> {
+    tree: &'a BTree,
+    // nodes from the root down to the current leaf, with the position in each
+    path: Vec<(BNode, u16)>,
+}
+
+impl BTree {
+    // iterate over the kvs with key >= start, in key order
+    pub fn scan(&self, start: &[u8]) -> Scan<'_> {
+        self.scan_from(self.root, start)
+    }
+
+    pub(crate) fn scan_from(&self, root: u64, start: &[u8]) -> Scan<'_> {
+        let mut scan = Scan {
+            tree: self,
+            path: Vec::new(),
+        };
+        if root == 0 {
+            return scan;
+        }
+
+        let mut node = self.persist.get_node(root);
+        loop {
+            let idx = node.lookup_le(start);
+            match node.n_type() {
+                BType::Node => {
+                    let ptr = node.get_ptr(idx);
+                    scan.path.push((node, idx));
+                    node = self.persist.get_node(ptr);
+                }
+                BType::LEAF => {
+                    let behind = node.get_key(idx) < start;
+                    scan.path.push((node, idx));
+                    if behind {
+                        scan.advance();
+                    }
+                    return scan;
+                }
+            }
+        }
+    }
+}
+
+impl Scan<'_> {
+    // move to the next kv, the path is empty past the last one
+    fn advance(&mut self) {
+        loop {
+            match self.path.last_mut() {
+                None => return,
+                Some((node, idx)) if *idx + 1 < node.n_keys() => {
+                    *idx += 1;
+                    break;
+                }
+                Some(_) => {
+                    self.path.pop();
+                }
+            }
+        }
+        loop {
+            let (node, idx) = self.path.last().unwrap();
+            if node.n_type() == BType::LEAF {
+                break;
+            }
+            let kid = self.tree.persist.get_node(node.get_ptr(*idx));
+            self.path.push((kid, 0));
+        }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, idx) = self.path.last()?;
+            let key = node.get_key(*idx).to_vec();
+            let val = node.get_val(*idx).to_vec();
+            self.advance();
+            // skip the empty sentinel key
+            if !key.is_empty() {
+                return Some((key, val));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b_tree::tests::MockPersist;
+    use crate::common::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn test_scan_empty() {
+        let tree = BTree::new(Box::new(MockPersist::new()));
+        assert_eq!(tree.scan(&[0x01]).count(), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        for i in (0..200u32).rev() {
+            tree.insert(&(i * 2).to_be_bytes(), &i.to_be_bytes());
+        }
+        let all: Vec<_> = tree.scan(&[0x00]).collect();
+        assert_eq!(all.len(), 200);
+        for (i, (key, val)) in all.iter().enumerate() {
+            assert_eq!(key, &(i as u32 * 2).to_be_bytes());
+            assert_eq!(val, &(i as u32).to_be_bytes());
+        }
+
+        // between keys, on a key, past the end
+        assert_eq!(tree.scan(&101u32.to_be_bytes()).next().unwrap().0, 102u32.to_be_bytes());
+        assert_eq!(tree.scan(&102u32.to_be_bytes()).next().unwrap().0, 102u32.to_be_bytes());
+        assert_eq!(tree.scan(&399u32.to_be_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn test_scan_big_kv() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        for b in [0xca, 0xff, 0xdf, 0x11] {
+            tree.insert(&[b; BTREE_MAX_KEY_SIZE], &[b; BTREE_MAX_VAL_SIZE]);
+        }
+        let keys: Vec<u8> = tree.scan(&[0x12]).map(|(k, _)| k[0]).collect();
+        assert_eq!(keys, vec![0xca, 0xdf, 0xff]);
+    }
+}
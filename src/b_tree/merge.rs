@@ -0,0 +1,151 @@
+use crate::b_tree::BTree;
+
+// combines a merge operand with the val stored under a key, None if there is none;
+// a merged val too large for the tree fails the merge before anything is written
+pub trait MergeOperator {
+    fn merge(&self, key: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+// u64 LE counters, shorter vals are zero padded
+pub struct Add;
+
+// the operand goes after the stored val
+pub struct Append;
+
+// the larger of the two by byte order
+pub struct Max;
+
+fn read_u64(val: &[u8]) -> u64 {
+    let mut data = [0; 8];
+    let n = val.len().min(8);
+    data[..n].copy_from_slice(&val[..n]);
+    u64::from_le_bytes(data)
+}
+
+impl MergeOperator for Add {
+    fn merge(&self, _: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let old = old.map_or(0, read_u64);
+        old.wrapping_add(read_u64(operand)).to_le_bytes().to_vec()
+    }
+}
+
+impl MergeOperator for Append {
+    fn merge(&self, _: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let mut val = old.unwrap_or_default().to_vec();
+        val.extend_from_slice(operand);
+        val
+    }
+}
+
+impl MergeOperator for Max {
+    fn merge(&self, _: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        match old {
+            Some(old) if old > operand => old.to_vec(),
+            _ => operand.to_vec(),
+        }
+    }
+}
+
+impl BTree {
+    // the operator merge() applies, it is not stored with the tree
+    pub fn set_merge_operator(&mut self, op: Box<dyn MergeOperator>) {
+        self.merge_op = Some(op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::remove_file;
+
+    use crate::b_tree::tests::MockPersist;
+    use crate::kv::KV;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_ops() {
+        assert_eq!(Add.merge(b"k", None, &5u64.to_le_bytes()), 5u64.to_le_bytes());
+        assert_eq!(Add.merge(b"k", Some(&5u64.to_le_bytes()), &[0x02]), 7u64.to_le_bytes());
+        assert_eq!(Add.merge(b"k", Some(&u64::MAX.to_le_bytes()), &[0x01]), 0u64.to_le_bytes());
+        assert_eq!(Append.merge(b"k", None, b"ab"), b"ab");
+        assert_eq!(Append.merge(b"k", Some(b"ab"), b"cd"), b"abcd");
+        assert_eq!(Max.merge(b"k", None, b"b"), b"b");
+        assert_eq!(Max.merge(b"k", Some(b"b"), b"a"), b"b");
+        assert_eq!(Max.merge(b"k", Some(b"b"), b"ba"), b"ba");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        tree.set_merge_operator(Box::new(Add));
+        let mut model = BTreeMap::new();
+        for i in 0..5000u64 {
+            let key = (i * 7919 % 500).to_be_bytes();
+            tree.merge(&key, &i.to_le_bytes()).unwrap();
+            *model.entry(key).or_insert(0u64) += i;
+        }
+        assert_eq!(tree.check(), Ok(()));
+        assert_eq!(tree.count(), 500);
+        for (key, sum) in &model {
+            assert_eq!(tree.get(key).unwrap(), sum.to_le_bytes());
+        }
+
+        // merges mix with plain writes
+        let key = 7u64.to_be_bytes();
+        tree.insert(&key, &100u64.to_le_bytes());
+        tree.merge(&key, &1u64.to_le_bytes()).unwrap();
+        assert_eq!(tree.get(&key).unwrap(), 101u64.to_le_bytes());
+        tree.delete(&key);
+        tree.merge(&key, &1u64.to_le_bytes()).unwrap();
+        assert_eq!(tree.get(&key).unwrap(), 1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_merge_kv() {
+        let _ = remove_file("test_merge_kv.db");
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_merge_kv.db")).unwrap()));
+        tree.set_merge_operator(Box::new(Append));
+        tree.merge(b"list", b"a").unwrap();
+        tree.begin();
+        for c in b'b'..=b'z' {
+            tree.merge(b"list", &[c]).unwrap();
+        }
+        tree.commit();
+        drop(tree);
+
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_merge_kv.db")).unwrap()));
+        assert_eq!(tree.get(b"list").unwrap(), b"abcdefghijklmnopqrstuvwxyz");
+        tree.set_merge_operator(Box::new(Max));
+        tree.merge(b"list", b"b").unwrap();
+        assert_eq!(tree.get(b"list").unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_merge_too_big() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        tree.set_merge_operator(Box::new(Append));
+        for i in 0..100u32 {
+            tree.insert(&i.to_be_bytes(), &[0xac; 100]);
+        }
+        let key = 50u32.to_be_bytes();
+        let root = tree.root();
+        let big = vec![0xca; tree.max_val_size() - 50];
+        assert_eq!(tree.merge(&key, &big), Err(format!("merged val of {} bytes", tree.max_val_size() + 50)));
+        // nothing changed
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.get(&key), Some(vec![0xac; 100]));
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_merge_without_operator() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        assert_eq!(tree.merge(b"k", b"v"), Err(String::from("no merge operator")));
+        tree.insert(b"k", b"v");
+        let root = tree.root();
+        assert_eq!(tree.merge(b"k", b"w"), Err(String::from("no merge operator")));
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.get(b"k"), Some(b"v".to_vec()));
+    }
+}
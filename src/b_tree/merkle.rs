@@ -0,0 +1,274 @@
+use crate::b_node::{BNode, BType};
+use crate::b_tree::BTree;
+use crate::b_tree::count::{COUNT_SIZE, KID_VAL_SIZE};
+
+pub type Hash = [u8; 32];
+
+// root hash of the empty tree
+pub const EMPTY_HASH: Hash = [0; 32];
+
+// node hash: sha256 of | type | (klen, key, vlen, val)... | for leaves and
+// | type | (klen, key, kid hash)... | for inner nodes, so it covers the whole subtree.
+// it covers how the kvs are split into nodes too: the same kvs in a tree of another
+// shape, as other ops or another page size leave it, have another root hash.
+// in an augmented tree the parent keeps the hash of a kid in its entry, a root hash
+// or a proof only hashes the nodes it reads
+#[derive(Clone, Debug, PartialEq)]
+pub enum Level {
+    Node(Vec<(Vec<u8>, Hash)>),
+    Leaf(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+// the nodes on the path to a key, from the root down
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proof {
+    pub levels: Vec<Level>,
+}
+
+impl BTree {
+    pub fn root_hash(&self) -> Hash {
+        if self.root == 0 {
+            return EMPTY_HASH;
+        }
+        self.node_hash(&self.persist.get_node(self.root))
+    }
+
+    pub(crate) fn node_hash(&self, node: &BNode) -> Hash {
+        self.level(node).hash()
+    }
+
+    // from the entry of the kid, or by walking it for entries without a hash
+    pub(crate) fn kid_hash(&self, node: &BNode, idx: u16) -> Hash {
+        match node.get_val(idx) {
+            val if val.len() == KID_VAL_SIZE => val[COUNT_SIZE..].try_into().unwrap(),
+            _ => self.hash_of(node.get_ptr(idx)),
+        }
+    }
+
+    // hashes of unchanged subtrees are kept between calls
+    pub fn hash_of(&self, ptr: u64) -> Hash {
+        if let Some(hash) = self.hashes.borrow().get(&ptr) {
+            return *hash;
+        }
+        let hash = self.node_hash(&self.persist.get_node(ptr));
+        self.hashes.borrow_mut().insert(ptr, hash);
+        hash
+    }
+
+    fn level(&self, node: &BNode) -> Level {
+        let keys = (0..node.n_keys()).map(|i| node.get_key(i).to_vec());
+        match node.n_type() {
+            BType::Node => Level::Node(keys.zip((0..node.n_keys()).map(|i| self.kid_hash(node, i))).collect()),
+            BType::LEAF => Level::Leaf(keys.zip((0..node.n_keys()).map(|i| node.get_val(i).to_vec())).collect()),
+        }
+    }
+
+    // proof that the key is in the tree with its val, or that it is not
+    pub fn prove(&self, key: &[u8]) -> Proof {
+        let mut levels = Vec::new();
+        let mut ptr = self.root;
+        while ptr != 0 {
+            let node = self.persist.get_node(ptr);
+            levels.push(self.level(&node));
+            ptr = match node.n_type() {
+                BType::Node => node.get_ptr(node.lookup_le(key)),
+                BType::LEAF => 0,
+            };
+        }
+        Proof { levels }
+    }
+}
+
+impl Level {
+    pub fn hash(&self) -> Hash {
+        let mut data = Vec::new();
+        match self {
+            Level::Node(kids) => {
+                data.push(BType::Node as u8);
+                for (key, hash) in kids {
+                    data.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    data.extend_from_slice(key);
+                    data.extend_from_slice(hash);
+                }
+            }
+            Level::Leaf(kvs) => {
+                data.push(BType::LEAF as u8);
+                for (key, val) in kvs {
+                    data.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    data.extend_from_slice(key);
+                    data.extend_from_slice(&(val.len() as u16).to_le_bytes());
+                    data.extend_from_slice(val);
+                }
+            }
+        }
+        sha256(&data)
+    }
+}
+
+impl Proof {
+    // the val of the key under the root hash, None if the key is absent;
+    // fails if the proof does not lead to the root hash
+    pub fn verify(&self, root: &Hash, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.levels.is_empty() {
+            return match *root == EMPTY_HASH {
+                true => Ok(None),
+                false => Err(String::from("empty proof for a non empty tree")),
+            };
+        }
+        // the path a lookup takes, each level must hash to the kid its parent follows
+        let mut expect = *root;
+        let mut found = None;
+        for (i, level) in self.levels.iter().enumerate() {
+            if level.hash() != expect {
+                return Err(format!("level {} does not match its hash", i));
+            }
+            let last = i + 1 == self.levels.len();
+            match level {
+                Level::Node(kids) if !last => {
+                    let idx = kids.iter().rposition(|(k, _)| k.as_slice() <= key).ok_or("key before the first kid")?;
+                    expect = kids[idx].1;
+                }
+                Level::Leaf(kvs) if last => {
+                    found = kvs.iter().find(|(k, _)| k.as_slice() == key).map(|(_, v)| v.clone());
+                }
+                _ => return Err(format!("level {} is out of place", i)),
+            }
+        }
+        Ok(found)
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// sha-256
+pub fn sha256(data: &[u8]) -> Hash {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(block[4 * i..4 * i + 4].try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut hash = [0; 32];
+    for (i, x) in h.iter().enumerate() {
+        hash[4 * i..4 * i + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b_tree::tests::{fill, MockPersist, open, open_new};
+
+    use super::*;
+
+    fn hex(hash: &Hash) -> String {
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // two blocks
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let mut a = BTree::new(Box::new(MockPersist::new()));
+        let mut b = open_new("test_root_hash.db");
+        b.set_augmented(true);
+        assert_eq!(a.root_hash(), EMPTY_HASH);
+        fill(&mut a, 1000, |i| vec![i as u8; 50]);
+        fill(&mut b, 1000, |i| vec![i as u8; 50]);
+        // the same ops give the same tree, wherever it is stored and its hashes are kept;
+        // this one is small enough for the hashes in the entries not to change its shape
+        assert_eq!(a.root_hash(), b.root_hash());
+        assert!(!a.hashes.borrow().is_empty());
+
+        let before = b.root_hash();
+        b.insert(&7u32.to_be_bytes(), &[0xac]);
+        assert_ne!(b.root_hash(), before);
+        b.insert(&7u32.to_be_bytes(), &a.get(&7u32.to_be_bytes()).unwrap());
+        assert_eq!(b.root_hash(), before);
+
+        // the hashes are in the file, a reopened tree only hashes the root
+        let hash = b.root_hash();
+        drop(b);
+        let b = open("test_root_hash.db");
+        assert_eq!(b.root_hash(), hash);
+        assert!(b.hashes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_proof() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        assert_eq!(tree.prove(&[0x01]).verify(&EMPTY_HASH, &[0x01]), Ok(None));
+        fill(&mut tree, 1000, |i| vec![i as u8; 50]);
+        let root = tree.root_hash();
+
+        let key = 123u32.to_be_bytes();
+        let proof = tree.prove(&key);
+        assert!(proof.levels.len() > 1);
+        assert_eq!(proof.verify(&root, &key), Ok(tree.get(&key)));
+        let absent = [0x00, 0x00, 0x00, 0x7b, 0x01];
+        assert_eq!(tree.prove(&absent).verify(&root, &absent), Ok(None));
+
+        // a proof only holds for its own key and root
+        assert!(proof.verify(&[0xac; 32], &key).is_err());
+        assert!(proof.verify(&root, &900u32.to_be_bytes()).is_err());
+        let mut forged = proof.clone();
+        if let Some(Level::Leaf(kvs)) = forged.levels.last_mut() {
+            kvs.iter_mut().find(|(k, _)| k == &key).unwrap().1 = vec![0xff];
+        }
+        assert!(forged.verify(&root, &key).is_err());
+    }
+}
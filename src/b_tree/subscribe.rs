@@ -0,0 +1,144 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::b_tree::BTree;
+
+// a change to a watched key, None for an absent val
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub key: Vec<u8>,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+// keys in [start, end), no end is past the last key
+pub(crate) struct Subscription {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    sender: Sender<Event>,
+}
+
+impl Subscription {
+    fn covers(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && self.end.as_ref().is_none_or(|end| key < end.as_slice())
+    }
+}
+
+// the first key past all keys with the prefix
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl BTree {
+    // the changes to keys in [start, end) are sent once they are committed;
+    // the channel is unbounded so the writer never waits on a reader,
+    // dropping the receiver ends the subscription
+    pub fn subscribe(&mut self, start: &[u8], end: &[u8]) -> Receiver<Event> {
+        self.subscribe_range(start.to_vec(), Some(end.to_vec()))
+    }
+
+    pub fn subscribe_prefix(&mut self, prefix: &[u8]) -> Receiver<Event> {
+        self.subscribe_range(prefix.to_vec(), prefix_end(prefix))
+    }
+
+    fn subscribe_range(&mut self, start: Vec<u8>, end: Option<Vec<u8>>) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscriptions.push(Subscription { start, end, sender });
+        receiver
+    }
+
+    // the val before a write, if a subscription covers the key
+    pub(crate) fn watch(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.subscriptions.iter().any(|s| s.covers(key)).then(|| self.get(key))
+    }
+
+    pub(crate) fn changed(&mut self, key: &[u8], watch: Option<Option<Vec<u8>>>) {
+        if let Some(old) = watch {
+            let new = self.get(key);
+            self.events.push(Event { key: key.to_vec(), old, new });
+        }
+    }
+
+    // send what the commit changed, in the order it was written
+    pub(crate) fn notify(&mut self) {
+        let events = std::mem::take(&mut self.events);
+        self.subscriptions.retain(|s| {
+            events.iter().filter(|e| s.covers(&e.key)).all(|e| s.sender.send(e.clone()).is_ok())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::b_tree::merge::Add;
+    use crate::b_tree::tests::MockPersist;
+
+    use super::*;
+
+    fn event(key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Event {
+        Event { key: key.to_vec(), old: old.map(|v| v.to_vec()), new: new.map(|v| v.to_vec()) }
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[0x01, 0xff]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        tree.set_merge_operator(Box::new(Add));
+        let users = tree.subscribe_prefix(b"user/");
+        let range = tree.subscribe(b"b", b"d");
+
+        tree.insert(b"user/1", b"a");
+        tree.insert(b"other", b"a");
+        assert_eq!(users.try_recv(), Ok(event(b"user/1", None, Some(b"a"))));
+        assert!(users.try_recv().is_err());
+
+        // nothing is sent before the commit
+        tree.begin();
+        tree.insert(b"user/1", b"b");
+        tree.delete(b"user/1");
+        tree.delete(b"user/2");
+        tree.merge(b"c", &[0x02]).unwrap();
+        tree.insert(b"d", b"a");
+        assert!(users.try_recv().is_err());
+        tree.commit();
+        assert_eq!(users.try_iter().collect::<Vec<_>>(), vec![
+            event(b"user/1", Some(b"a"), Some(b"b")),
+            event(b"user/1", Some(b"b"), None),
+        ]);
+        assert_eq!(range.try_iter().collect::<Vec<_>>(), vec![
+            event(b"c", None, Some(&2u64.to_le_bytes())),
+        ]);
+
+        // a dropped receiver is forgotten on the next send
+        drop(range);
+        tree.insert(b"c", b"a");
+        assert_eq!(tree.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn test_subscribe_thread() {
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        let events = tree.subscribe(&[0x00], &[0xff]);
+        let reader = thread::spawn(move || events.iter().map(|e| e.new.unwrap()).collect::<Vec<_>>());
+        for i in 0..100u8 {
+            tree.insert(&[i], &[i]);
+        }
+        // the reader ends once the tree and its senders are gone
+        drop(tree);
+        assert_eq!(reader.join().unwrap(), (0..100u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+}
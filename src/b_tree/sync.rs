@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use crate::b_node::BType;
+use crate::b_tree::BTree;
+use crate::b_tree::diff::{Change, Expand, Item, Local, Walk};
+use crate::b_tree::merkle::Hash;
+
+// the replica asks for nodes of the primary by ptr: | ptr u64 |, 0 for the root;
+// root answer: | ptr | height | hash |
+// node answer: | type u8 | n u16 | (klen u16, key, ptr, hash)... | for inner nodes,
+// | type u8 | n u16 | (klen u16, key, vlen u16, val)... | for leaves
+const ROOT: u64 = 0;
+const DONE: u64 = u64::MAX;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Pulled {
+    // nodes received from the primary
+    pub nodes: usize,
+    // keys inserted, updated or deleted on the replica
+    pub changes: usize,
+}
+
+impl BTree {
+    // answer the node requests of a replica until it is done
+    pub fn serve(&self, stream: &mut (impl Read + Write)) -> Result<(), String> {
+        // only nodes of the tree may be asked for
+        let mut offered = HashSet::new();
+        loop {
+            let mut msg = Vec::new();
+            match read_u64(stream)? {
+                DONE => return Ok(()),
+                ROOT => {
+                    offered.insert(self.root);
+                    msg.extend_from_slice(&self.root.to_le_bytes());
+                    msg.extend_from_slice(&(self.height(self.root) as u64).to_le_bytes());
+                    msg.extend_from_slice(&self.root_hash());
+                }
+                ptr if offered.contains(&ptr) => {
+                    let node = self.persist.get_node(ptr);
+                    msg.push(node.n_type() as u8);
+                    msg.extend_from_slice(&node.n_keys().to_le_bytes());
+                    for i in 0..node.n_keys() {
+                        let key = node.get_key(i);
+                        msg.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                        msg.extend_from_slice(key);
+                        if node.n_type() == BType::Node {
+                            let kid = node.get_ptr(i);
+                            offered.insert(kid);
+                            msg.extend_from_slice(&kid.to_le_bytes());
+                            msg.extend_from_slice(&self.kid_hash(&node, i));
+                        } else {
+                            let val = node.get_val(i);
+                            msg.extend_from_slice(&(val.len() as u16).to_le_bytes());
+                            msg.extend_from_slice(val);
+                        }
+                    }
+                }
+                ptr => return Err(format!("page {} was not offered", ptr)),
+            }
+            stream.write_all(&msg).map_err(|e| e.to_string())?;
+            stream.flush().map_err(|e| e.to_string())?;
+        }
+    }
+
+    // bring the tree up to date with the primary at the other end of the stream;
+    // subtrees with the same hash on both sides are skipped, only the nodes that
+    // differ cross the stream. the changes go in as one commit
+    pub fn pull(&mut self, stream: &mut (impl Read + Write)) -> Result<Pulled, String> {
+        assert!(!self.in_txn);
+        let mut pulled = Pulled::default();
+        write_u64(stream, ROOT)?;
+        let (root, height) = (read_u64(stream)?, read_u64(stream)? as usize);
+        let hash = read_hash(stream)?;
+
+        let theirs = if root == 0 { Vec::new() } else { vec![Item::Node(root, Vec::new(), height, Some(hash))] };
+        let ours = Local { tree: self, hashed: true };
+        let start = ours.start(self.root);
+        let remote = Remote { stream: &mut *stream, nodes: &mut pulled.nodes };
+        let mut walk = Walk::new(ours, start, remote, theirs);
+        // key, the primary val or None to delete
+        let mut changes: Vec<(Vec<u8>, Option<Vec<u8>>)> = Vec::new();
+        while let Some(change) = walk.next_change()? {
+            changes.push(match change {
+                Change::Removed(k, _) => (k, None),
+                Change::Added(k, v) | Change::Changed(k, _, v) => (k, Some(v)),
+            });
+        }
+        drop(walk);
+        write_u64(stream, DONE)?;
+
+        // nothing goes in unless all of it fits
+        for (key, val) in &changes {
+            if key.len() > self.max_key_size() {
+                return Err(format!("key of {} bytes", key.len()));
+            }
+            if let Some(val) = val.as_ref().filter(|v| v.len() > self.max_val_size()) {
+                return Err(format!("val of {} bytes", val.len()));
+            }
+        }
+        self.begin();
+        for (key, val) in &changes {
+            match val {
+                Some(val) => self.insert(key, val),
+                None => {
+                    self.delete(key);
+                }
+            }
+        }
+        self.commit();
+        pulled.changes = changes.len();
+        Ok(pulled)
+    }
+}
+
+// the primary's side of the walk, read from the stream
+struct Remote<'a, S> {
+    stream: &'a mut S,
+    // nodes received
+    nodes: &'a mut usize,
+}
+
+impl<S: Read + Write> Expand for Remote<'_, S> {
+    fn expand(&mut self, side: &mut Vec<Item>) -> Result<(), String> {
+        let Some(Item::Node(ptr, _, height, _)) = side.pop() else { unreachable!() };
+        let stream = &mut *self.stream;
+        write_u64(stream, ptr)?;
+        let mut head = [0; 3];
+        stream.read_exact(&mut head).map_err(|e| e.to_string())?;
+        let n = u16::from_le_bytes([head[1], head[2]]) as usize;
+        let mut kids = Vec::with_capacity(n);
+        for _ in 0..n {
+            let key = read_bytes(stream)?;
+            kids.push(match head[0] {
+                t if t == BType::Node as u8 && height > 0 => {
+                    let kid = read_u64(stream)?;
+                    Item::Node(kid, key, height - 1, Some(read_hash(stream)?))
+                }
+                t if t == BType::LEAF as u8 && height == 0 => Item::Kv(key, read_bytes(stream)?),
+                t => return Err(format!("node of type {} at height {}", t, height)),
+            });
+        }
+        side.extend(kids.into_iter().rev());
+        *self.nodes += 1;
+        Ok(())
+    }
+}
+
+fn read_u64(stream: &mut impl Read) -> Result<u64, String> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_u64(stream: &mut impl Write, n: u64) -> Result<(), String> {
+    stream.write_all(&n.to_le_bytes()).map_err(|e| e.to_string())?;
+    stream.flush().map_err(|e| e.to_string())
+}
+
+fn read_hash(stream: &mut impl Read) -> Result<Hash, String> {
+    let mut hash = [0; 32];
+    stream.read_exact(&mut hash).map_err(|e| e.to_string())?;
+    Ok(hash)
+}
+
+// | len u16 | bytes |
+fn read_bytes(stream: &mut impl Read) -> Result<Vec<u8>, String> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).map_err(|e| e.to_string())?;
+    let mut data = vec![0; u16::from_le_bytes(len) as usize];
+    stream.read_exact(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{copy, remove_file};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use crate::b_tree::tests::{fill, open, open_new};
+    use crate::kv::{KV, Storage};
+
+    use super::*;
+
+    fn open_augmented(path: &str) -> BTree {
+        let mut tree = open(path);
+        tree.set_augmented(true);
+        tree
+    }
+
+    // a primary serving one replica from another thread
+    fn primary(path: &'static str, mut stream: impl Read + Write + Send + 'static) -> thread::JoinHandle<()> {
+        thread::spawn(move || open(path).serve(&mut stream).unwrap())
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut tree = open_new("test_sync_primary.db");
+        tree.set_augmented(true);
+        fill(&mut tree, 3000, |i| vec![i as u8; 60]);
+        drop(tree);
+        copy("test_sync_primary.db", "test_sync_replica.db").unwrap();
+
+        let mut tree = open_augmented("test_sync_primary.db");
+        tree.begin();
+        tree.insert(&5u32.to_be_bytes(), &[0xac]);
+        tree.delete(&2000u32.to_be_bytes());
+        tree.insert(&[0xff; 8], &[0xca]);
+        tree.commit();
+        drop(tree);
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let server = primary("test_sync_primary.db", a);
+        let mut replica = open_augmented("test_sync_replica.db");
+        let pulled = replica.pull(&mut b).unwrap();
+        server.join().unwrap();
+        assert_eq!(pulled.changes, 3);
+        // the paths to three leaves, not the whole tree
+        assert!(pulled.nodes < 10, "{:?}", pulled);
+        // the kid hashes come from the parents, no subtree was walked to hash it
+        assert!(replica.hashes.borrow().is_empty());
+        assert_eq!(replica.check(), Ok(()));
+        assert!(replica.scan(&[0x00]).eq(open("test_sync_primary.db").scan(&[0x00])));
+
+        // nothing left to do
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let server = primary("test_sync_primary.db", a);
+        assert_eq!(replica.pull(&mut b).unwrap().changes, 0);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_sync_tcp() {
+        let mut tree = open_new("test_sync_tcp_primary.db");
+        fill(&mut tree, 500, |i| vec![i as u8; 60]);
+        drop(tree);
+        // extra keys to delete and a different shape
+        let mut replica = open_new("test_sync_tcp_replica.db");
+        for i in (0..2000u32).step_by(3) {
+            replica.insert(&i.to_be_bytes(), &[0x01; 10]);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            open("test_sync_tcp_primary.db").serve(&mut stream).unwrap();
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        replica.pull(&mut stream).unwrap();
+        server.join().unwrap();
+        assert_eq!(replica.check(), Ok(()));
+        assert!(replica.scan(&[0x00]).eq(open("test_sync_tcp_primary.db").scan(&[0x00])));
+    }
+
+    #[test]
+    fn test_sync_empty() {
+        open_new("test_sync_empty_primary.db");
+        let mut replica = open_new("test_sync_empty_replica.db");
+        fill(&mut replica, 100, |i| vec![i as u8; 60]);
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let server = primary("test_sync_empty_primary.db", a);
+        assert_eq!(replica.pull(&mut b).unwrap(), Pulled { nodes: 0, changes: 100 });
+        server.join().unwrap();
+        assert_eq!(replica.scan(&[0x00]).count(), 0);
+
+        // asking for a page the primary never offered
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || open("test_sync_empty_primary.db").serve(&mut a));
+        write_u64(&mut b, 1).unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_sync_too_big() {
+        let _ = remove_file("test_sync_big_primary.db");
+        // a primary with larger pages takes keys the replica cannot
+        let kv = KV::open_with_page_size(String::from("test_sync_big_primary.db"), Storage::Mmap, 8192).unwrap();
+        let mut tree = BTree::new(Box::new(kv));
+        tree.insert(&[0xac; 1500], b"1");
+        drop(tree);
+        let mut replica = open_new("test_sync_big_replica.db");
+        replica.insert(b"a", b"1");
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let server = primary("test_sync_big_primary.db", a);
+        assert_eq!(replica.pull(&mut b), Err(String::from("key of 1500 bytes")));
+        server.join().unwrap();
+        assert_eq!(replica.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(replica.count(), 1);
+    }
+}
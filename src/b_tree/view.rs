@@ -0,0 +1,148 @@
+use crate::b_tree::BTree;
+use crate::b_tree::iter::Scan;
+use crate::kv::history::Version;
+
+// read-only access to a kept version of the tree
+pub struct View<'a> {
+    tree: &'a BTree,
+    version: Version,
+}
+
+impl BTree {
+    // keep the roots of the last `n` commits readable
+    pub fn retain(&mut self, n: usize) -> Result<(), String> {
+        self.persist.set_retain(n)
+    }
+
+    // keep the last committed root under `name` until untagged,
+    // a tag of the same name moves
+    pub fn tag(&mut self, name: &str) -> Result<u64, String> {
+        self.persist.tag(name)
+    }
+
+    pub fn untag(&mut self, name: &str) -> Result<(), String> {
+        self.persist.untag(name)
+    }
+
+    pub fn versions(&self) -> Vec<Version> {
+        self.persist.versions()
+    }
+
+    pub fn view(&self, version: u64) -> Option<View<'_>> {
+        let version = self.versions().into_iter().find(|v| v.version == version)?;
+        Some(View { tree: self, version })
+    }
+
+    pub fn view_tag(&self, name: &str) -> Option<View<'_>> {
+        let version = self.versions().into_iter().find(|v| v.name.as_deref() == Some(name))?;
+        Some(View { tree: self, version })
+    }
+}
+
+impl View<'_> {
+    pub fn version(&self) -> u64 {
+        self.version.version
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        assert_ne!(key.len(), 0);
+        assert!(key.len() <= self.tree.max_key_size());
+        if self.version.root == 0 {
+            return None;
+        }
+        self.tree.tree_get(&self.tree.persist.get_node(self.version.root), key)
+    }
+
+    pub fn scan(&self, start: &[u8]) -> Scan<'_> {
+        self.tree.scan_from(self.version.root, start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::b_tree::tests::{fill, MockPersist, open, open_new};
+
+    use super::*;
+
+    #[test]
+    fn test_view_retain() {
+        let mut tree = open_new("test_view_retain.db");
+        tree.retain(3).unwrap();
+        for round in 1..=5 {
+            fill(&mut tree, 200, |_| vec![round; 100]);
+        }
+        let versions: Vec<u64> = tree.versions().iter().map(|v| v.version).collect();
+        assert_eq!(versions.len(), 3);
+        assert!(tree.view(versions[0] - 1).is_none());
+
+        // each kept version reads as it was committed
+        for (v, round) in versions.iter().zip(3u8..) {
+            let view = tree.view(*v).unwrap();
+            assert_eq!(view.get(&7u32.to_be_bytes()), Some(vec![round; 100]));
+            assert!(view.scan(&[0x00]).all(|(_, val)| val == vec![round; 100]));
+            assert_eq!(view.scan(&[0x00]).count(), 200);
+        }
+        assert_eq!(tree.check(), Ok(()));
+        drop(tree);
+
+        let tree = open("test_view_retain.db");
+        assert_eq!(tree.versions().len(), 3);
+        assert_eq!(tree.view(versions[0]).unwrap().get(&7u32.to_be_bytes()), Some(vec![3; 100]));
+    }
+
+    #[test]
+    fn test_view_tag() {
+        let mut tree = open_new("test_view_tag.db");
+        fill(&mut tree, 200, |_| vec![1; 100]);
+        let v = tree.tag("one").unwrap();
+        assert!(tree.tag("").is_err());
+        // tags within a txn see the committed root
+        tree.begin();
+        tree.delete(&7u32.to_be_bytes());
+        tree.tag("still one").unwrap();
+        tree.commit();
+        for round in 2..6 {
+            fill(&mut tree, 200, |_| vec![round; 100]);
+        }
+        assert_eq!(tree.check(), Ok(()));
+        drop(tree);
+
+        let mut tree = open("test_view_tag.db");
+        let view = tree.view_tag("one").unwrap();
+        assert_eq!(view.version(), v);
+        assert_eq!(view.get(&7u32.to_be_bytes()), Some(vec![1; 100]));
+        assert_eq!(tree.view_tag("still one").unwrap().scan(&[0x00]).count(), 200);
+        assert_eq!(tree.get(&7u32.to_be_bytes()), Some(vec![5; 100]));
+        tree.untag("one").unwrap();
+        assert!(tree.untag("one").is_err());
+        assert!(tree.view_tag("one").is_none());
+    }
+
+    #[test]
+    fn test_view_gc() {
+        let mut tree = open_new("test_view_gc.db");
+        fill(&mut tree, 200, |_| vec![1; 100]);
+        tree.tag("one").unwrap();
+        for round in 2..10 {
+            fill(&mut tree, 200, |_| vec![round; 100]);
+        }
+        // every version in between is freed, only the tagged one stays
+        let used = tree.persist.used();
+        let free = tree.persist.free_pages().len();
+        assert!(free as u64 * 2 > used, "{} of {}", free, used);
+        assert_eq!(tree.check(), Ok(()));
+
+        // the pages of the tag come back after the next commits
+        tree.untag("one").unwrap();
+        for round in 10..20 {
+            fill(&mut tree, 200, |_| vec![round; 100]);
+        }
+        assert_eq!(tree.persist.used(), used);
+        assert_eq!(tree.check(), Ok(()));
+
+        // without a db file there is no history
+        let mut tree = BTree::new(Box::new(MockPersist::new()));
+        assert!(tree.tag("one").is_err());
+        assert!(tree.versions().is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::io::stdout;
+use std::process::exit;
+
+use my_db::b_tree::BTree;
+use my_db::inspect::Inspector;
+use my_db::kv::KV;
+
+fn usage() -> ! {
+    eprintln!("usage: inspect <db file> [meta | tree | page <n> | check]");
+    exit(2);
+}
+
+fn check(path: &str) -> ! {
+    // KV::new makes a db of a missing or empty file, that is not one to check
+    let opened = Inspector::open(path).and_then(|ins| match ins.page(0) {
+        Ok(_) => KV::new(String::from(path)),
+        Err(_) => Err(String::from("no meta page")),
+    });
+    let kv = match opened {
+        Ok(kv) => kv,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            exit(1);
+        }
+    };
+    match BTree::new(Box::new(kv)).check() {
+        Ok(()) => {
+            println!("ok");
+            exit(0);
+        }
+        Err(errs) => {
+            for e in &errs {
+                println!("{}", e);
+            }
+            println!("{} problems", errs.len());
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        usage();
+    }
+    if args.get(2).map(|s| s.as_str()) == Some("check") {
+        check(&args[1]);
+    }
+    let ins = match Inspector::open(&args[1]) {
+        Ok(ins) => ins,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            exit(1);
+        }
+    };
+
+    let mut out = stdout().lock();
+    let r = match args.get(2).map(|s| s.as_str()) {
+        None => ins.dump_meta(&mut out).and_then(|_| ins.dump_tree(&mut out)),
+        Some("meta") => ins.dump_meta(&mut out),
+        Some("tree") => ins.dump_tree(&mut out),
+        Some("page") => match args.get(3).and_then(|n| n.parse().ok()) {
+            Some(ptr) => ins.dump_page(&mut out, ptr),
+            None => usage(),
+        },
+        Some(_) => usage(),
+    };
+    r.unwrap();
+}
@@ -1,5 +1,4 @@
 use std::fs::OpenOptions;
-use std::io::Write;
 use std::num::NonZeroUsize;
 use std::os::fd::AsFd;
 use std::slice::from_raw_parts_mut;
@@ -11,6 +10,7 @@ fn main() {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open("test").unwrap();
     file.set_len(0x10).unwrap();
     // file.set_len(0x2000).unwrap();
@@ -32,7 +32,7 @@ fn main() {
              MapFlags::MAP_SHARED, file.as_fd(), 0).unwrap()
     };
 
-    let mut slice = unsafe {
+    let slice = unsafe {
         from_raw_parts_mut(m.as_ptr() as *mut u8, 0x4000)
     };
 
@@ -45,7 +45,7 @@ fn main() {
              MapFlags::MAP_SHARED, file.as_fd(), 0x4000).unwrap()
     };
 
-    let mut slice2 = unsafe {
+    let slice2 = unsafe {
         from_raw_parts_mut(m1.as_ptr() as *mut u8, 0x4000)
     };
 
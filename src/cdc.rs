@@ -0,0 +1,283 @@
+use crate::b_tree::BTree;
+use crate::kv::KV;
+
+// the tree holds three key ranges: the next sequence number under SEQ,
+// the change log under LOG as | LOG | seq u64 BE | key | with vals | op | val |,
+// and the kvs under DATA as | DATA | key |; a commit writes all of them at once
+const SEQ: u8 = 0;
+const LOG: u8 = 1;
+const DATA: u8 = 2;
+const OP_PUT: u8 = 1;
+const OP_DEL: u8 = 2;
+
+// a committed put, or a delete when the val is None
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mutation {
+    pub seq: u64,
+    pub key: Vec<u8>,
+    pub val: Option<Vec<u8>>,
+}
+
+// kvs whose changes are kept in order for readers to catch up on
+pub struct CdcDB {
+    tree: BTree,
+    // seq of the next change, they start at 1
+    seq: u64,
+    // log records kept after a commit, 0 keeps all
+    retain: u64,
+    in_txn: bool,
+}
+
+fn prefixed(prefix: u8, key: &[u8]) -> Vec<u8> {
+    let mut data = vec![prefix];
+    data.extend_from_slice(key);
+    data
+}
+
+fn log_key(seq: u64, key: &[u8]) -> Vec<u8> {
+    let mut data = vec![LOG];
+    data.extend_from_slice(&seq.to_be_bytes());
+    data.extend_from_slice(key);
+    data
+}
+
+fn decode(key: &[u8], val: &[u8]) -> Mutation {
+    Mutation {
+        seq: u64::from_be_bytes(key[1..9].try_into().unwrap()),
+        key: key[9..].to_vec(),
+        val: match val[0] {
+            OP_PUT => Some(val[1..].to_vec()),
+            _ => None,
+        },
+    }
+}
+
+impl CdcDB {
+    pub fn open(path: &str) -> Result<CdcDB, String> {
+        let tree = BTree::new(Box::new(KV::new(String::from(path))?));
+        let seq = match tree.get(&[SEQ]) {
+            Some(seq) => u64::from_le_bytes(seq.try_into().map_err(|_| "bad sequence number")?),
+            None => 1,
+        };
+        Ok(CdcDB { tree, seq, retain: 0, in_txn: false })
+    }
+
+    // room for the prefix and the seq
+    pub fn max_key_size(&self) -> usize {
+        self.tree.max_key_size() - 9
+    }
+    pub fn max_val_size(&self) -> usize {
+        self.tree.max_val_size() - 1
+    }
+
+    // keep only the last `n` changes from the next commit on, 0 keeps all
+    pub fn set_retain(&mut self, n: u64) {
+        self.retain = n;
+    }
+
+    // seq of the last change, 0 before any
+    pub fn last_seq(&self) -> u64 {
+        self.seq - 1
+    }
+
+    // the oldest change still in the log, or the next one if it is empty
+    pub fn first_seq(&self) -> u64 {
+        match self.tree.scan(&[LOG]).next() {
+            Some((k, v)) if k[0] == LOG => decode(&k, &v).seq,
+            _ => self.seq,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        assert_ne!(key.len(), 0);
+        self.tree.get(&prefixed(DATA, key))
+    }
+
+    pub fn scan(&self, start: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.tree
+            .scan(&prefixed(DATA, start))
+            .take_while(|(k, _)| k[0] == DATA)
+            .map(|(k, v)| (k[1..].to_vec(), v))
+    }
+
+    // group the following ops into one commit
+    pub fn begin(&mut self) {
+        self.in_txn = true;
+        self.tree.begin();
+    }
+    // the changes, the truncation and the next seq go in one flush
+    pub fn commit(&mut self) {
+        self.truncate_retained();
+        self.tree.insert(&[SEQ], &self.seq.to_le_bytes());
+        self.in_txn = false;
+        self.tree.commit();
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
+        assert_ne!(key.len(), 0);
+        assert!(key.len() <= self.max_key_size());
+        assert!(val.len() <= self.max_val_size());
+        self.write(key, Some(val));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        assert_ne!(key.len(), 0);
+        if self.get(key).is_none() {
+            return false;
+        }
+        self.write(key, None);
+        true
+    }
+
+    fn write(&mut self, key: &[u8], val: Option<&[u8]>) {
+        let single = !self.in_txn;
+        if single {
+            self.begin();
+        }
+        let record = match val {
+            Some(val) => {
+                self.tree.insert(&prefixed(DATA, key), val);
+                prefixed(OP_PUT, val)
+            }
+            None => {
+                self.tree.delete(&prefixed(DATA, key));
+                vec![OP_DEL]
+            }
+        };
+        self.tree.insert(&log_key(self.seq, key), &record);
+        self.seq += 1;
+        if single {
+            self.commit();
+        }
+    }
+
+    // changes after seq `since` in order; fails if some of them were truncated
+    pub fn changes(&self, since: u64) -> Result<impl Iterator<Item = Mutation> + '_, String> {
+        // seqs start at 1, so first - 1 is the last seq a reader may have seen
+        let first = self.first_seq();
+        if since < first - 1 {
+            return Err(format!("changes before {} are truncated", first));
+        }
+        Ok(self.tree
+            .scan(&log_key(since, &[]))
+            .take_while(|(k, _)| k[0] == LOG)
+            .map(|(k, v)| decode(&k, &v))
+            .filter(move |m| m.seq > since))
+    }
+
+    // drop the changes before seq `before`
+    pub fn truncate(&mut self, before: u64) {
+        let single = !self.in_txn;
+        if single {
+            self.tree.begin();
+        }
+        let old: Vec<Vec<u8>> = self.tree
+            .scan(&[LOG])
+            .take_while(|(k, v)| k[0] == LOG && decode(k, v).seq < before)
+            .map(|(k, _)| k)
+            .collect();
+        for key in old {
+            self.tree.delete(&key);
+        }
+        if single {
+            self.tree.commit();
+        }
+    }
+
+    fn truncate_retained(&mut self) {
+        if self.retain > 0 && self.seq > self.retain {
+            self.truncate(self.seq - self.retain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    use crate::fault::{Fault, FaultyPersist};
+    use crate::kv::Storage;
+
+    use super::*;
+
+    fn put(seq: u64, key: &[u8], val: &[u8]) -> Mutation {
+        Mutation { seq, key: key.to_vec(), val: Some(val.to_vec()) }
+    }
+
+    #[test]
+    fn test_cdc() {
+        let _ = remove_file("test_cdc.db");
+        let mut db = CdcDB::open("test_cdc.db").unwrap();
+        assert_eq!(db.last_seq(), 0);
+        assert_eq!(db.changes(0).unwrap().count(), 0);
+        db.insert(b"a", b"1");
+        db.begin();
+        db.insert(b"b", b"2");
+        db.insert(b"a", b"3");
+        assert!(db.delete(b"b"));
+        assert!(!db.delete(b"c"));
+        db.commit();
+        assert_eq!(db.last_seq(), 4);
+        assert_eq!(db.changes(0).unwrap().collect::<Vec<_>>(), vec![
+            put(1, b"a", b"1"),
+            put(2, b"b", b"2"),
+            put(3, b"a", b"3"),
+            Mutation { seq: 4, key: b"b".to_vec(), val: None },
+        ]);
+        assert_eq!(db.changes(2).unwrap().next().unwrap().seq, 3);
+        assert_eq!(db.changes(4).unwrap().count(), 0);
+        assert_eq!(db.changes(u64::MAX).unwrap().count(), 0);
+        assert_eq!(db.scan(&[0x00]).collect::<Vec<_>>(), vec![(b"a".to_vec(), b"3".to_vec())]);
+        drop(db);
+
+        // seqs go on after a reopen, and after a truncation
+        let mut db = CdcDB::open("test_cdc.db").unwrap();
+        db.truncate(5);
+        assert_eq!(db.first_seq(), 5);
+        assert!(db.changes(3).is_err());
+        assert_eq!(db.changes(4).unwrap().count(), 0);
+        drop(db);
+        let mut db = CdcDB::open("test_cdc.db").unwrap();
+        db.insert(b"c", b"4");
+        assert_eq!(db.changes(4).unwrap().collect::<Vec<_>>(), vec![put(5, b"c", b"4")]);
+        assert_eq!(db.get(b"a"), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_cdc_retain() {
+        let _ = remove_file("test_cdc_retain.db");
+        let mut db = CdcDB::open("test_cdc_retain.db").unwrap();
+        db.set_retain(100);
+        for i in 0..1000u32 {
+            db.insert(&(i % 300).to_be_bytes(), &i.to_be_bytes());
+        }
+        assert_eq!(db.first_seq(), 901);
+        assert!(db.changes(899).is_err());
+        let changes: Vec<Mutation> = db.changes(900).unwrap().collect();
+        assert_eq!(changes.len(), 100);
+        assert_eq!(changes[99], put(1000, &99u32.to_be_bytes(), &999u32.to_be_bytes()));
+        // the kvs are not truncated with their changes
+        assert_eq!(db.scan(&[0x00]).count(), 300);
+        assert_eq!(db.tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_cdc_one_flush() {
+        let _ = remove_file("test_cdc_one_flush.db");
+        let persist = FaultyPersist::open("test_cdc_one_flush.db", Storage::Mmap, Fault::Flush(3)).unwrap();
+        // each commit flushes once, even with a truncation, so the third one is c
+        let tree = BTree::new(Box::new(persist));
+        let mut db = CdcDB { tree, seq: 1, retain: 1, in_txn: false };
+        db.insert(b"a", b"1");
+        db.insert(b"b", b"2");
+        assert!(catch_unwind(AssertUnwindSafe(|| db.insert(b"c", b"3"))).is_err());
+        drop(db);
+
+        let mut db = CdcDB::open("test_cdc_one_flush.db").unwrap();
+        assert_eq!(db.last_seq(), 2);
+        assert_eq!(db.changes(1).unwrap().collect::<Vec<_>>(), vec![put(2, b"b", b"2")]);
+        db.insert(b"c", b"3");
+        assert_eq!(db.last_seq(), 3);
+    }
+}
@@ -2,11 +2,33 @@ use lazy_static::lazy_static;
 use nix::libc::{_SC_PAGESIZE, sysconf};
 
 use crate::b_node::BNode;
+use crate::kv::backup::Snapshot;
+use crate::kv::Durability;
+use crate::kv::history::Version;
 
 pub const HEADER: usize = 4;
+// default page size, a db picks its own at creation
 pub const BTREE_PAGE_SIZE: usize = 4096;
-pub const BTREE_MAX_KEY_SIZE: usize = 1000;
-pub const BTREE_MAX_VAL_SIZE: usize = 3000;
+pub const BTREE_MAX_KEY_SIZE: usize = max_key_size(BTREE_PAGE_SIZE);
+pub const BTREE_MAX_VAL_SIZE: usize = max_val_size(BTREE_PAGE_SIZE);
+// node offsets are u16 and a node may hold two pages before it is split
+pub const MIN_PAGE_SIZE: usize = 1024;
+pub const MAX_PAGE_SIZE: usize = 32768;
+
+// a leaf with the largest kv still fits a page
+pub const fn max_key_size(page_size: usize) -> usize {
+    page_size / 4 - 24
+}
+pub const fn max_val_size(page_size: usize) -> usize {
+    page_size / 4 * 3 - 72
+}
+
+pub fn check_page_size(page_size: usize) -> Result<(), String> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(format!("page size {} is not a power of two in [{}, {}]", page_size, MIN_PAGE_SIZE, MAX_PAGE_SIZE));
+    }
+    Ok(())
+}
 
 lazy_static! {
     pub static ref SYS_PAGE_SIZE: usize = get_page_size();
@@ -17,9 +39,45 @@ pub trait Persist {
     fn new_node(&mut self, node: &BNode) -> u64;
     fn del_node(&mut self, ptr: u64);
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // pages [1, used) are either in the tree or free
+    fn used(&self) -> u64;
+    fn free_pages(&self) -> Vec<u64>;
     fn get_root(&self) -> u64;
     fn set_root(&mut self, root: u64);
     fn flush(&mut self);
+    fn page_size(&self) -> usize {
+        BTREE_PAGE_SIZE
+    }
+    // give the free pages at the end of the file back, after a flush
+    fn truncate(&mut self) {}
+    // pin the committed state
+    fn snapshot(&mut self) -> Result<Snapshot, String> {
+        Err(String::from("no snapshots without a db file"))
+    }
+    // committed roots kept readable, their pages are not reused
+    fn versions(&self) -> Vec<Version> {
+        Vec::new()
+    }
+    fn set_retain(&mut self, _retain: usize) -> Result<(), String> {
+        Err(String::from("no history without a db file"))
+    }
+    // keep the committed root under a name, returns its version
+    fn tag(&mut self, _name: &str) -> Result<u64, String> {
+        Err(String::from("no history without a db file"))
+    }
+    fn untag(&mut self, _name: &str) -> Result<(), String> {
+        Err(String::from("no history without a db file"))
+    }
+    // how commits reach the disk
+    fn set_durability(&mut self, _durability: Durability) {}
+    fn durability(&self) -> Durability {
+        Durability::Sync
+    }
+    // put the deferred commits on disk
+    fn sync(&mut self) {}
 }
 
 fn get_page_size() -> usize {
@@ -0,0 +1,364 @@
+use std::cell::RefCell;
+use std::panic::{AssertUnwindSafe, catch_unwind, resume_unwind};
+use std::rc::Rc;
+
+use crate::b_node::BNode;
+use crate::common::Persist;
+use crate::kv::{Durability, KV, Pages, Storage};
+use crate::kv::backup::Snapshot;
+use crate::kv::history::Version;
+
+// where the wrapped persist goes down, counting from 1
+pub enum Fault {
+    // the nth page written to the file panics, the pages of the flush written
+    // before it stay but the meta page does not, as if the crash hit mid-flush
+    Write(u64),
+    // the nth flush panics before anything is written
+    Flush(u64),
+    // the nth flush writes its pages but not the meta page, as if the crash
+    // hit between the page sync and the meta write
+    TornFlush(u64),
+}
+
+// what reaches the file under a faulty persist
+#[derive(Default)]
+struct Disk {
+    writes: u64,
+    // the page write that crashes
+    write_fault: Option<u64>,
+    // the meta page is not written
+    torn: bool,
+    // nothing is written
+    crashed: bool,
+}
+
+// the pages of the db file, written as long as the disk has not crashed
+struct FaultyPages {
+    inner: Box<dyn Pages>,
+    disk: Rc<RefCell<Disk>>,
+}
+
+impl Pages for FaultyPages {
+    fn read(&self, ptr: u64) -> Vec<u8> {
+        self.inner.read(ptr)
+    }
+
+    // the pages written before the crash are in the file, this one and the rest are not
+    fn write(&mut self, ptr: u64, data: &[u8]) {
+        let mut disk = self.disk.borrow_mut();
+        if disk.crashed || (disk.torn && ptr == 0) {
+            return;
+        }
+        disk.writes += 1;
+        if disk.write_fault == Some(disk.writes) {
+            disk.crashed = true;
+            panic!("simulated crash at page write to {}", ptr);
+        }
+        self.inner.write(ptr, data)
+    }
+
+    fn grow(&mut self, size: usize) {
+        self.inner.grow(size)
+    }
+
+    fn flush(&mut self) {
+        if !self.disk.borrow().crashed {
+            self.inner.flush()
+        }
+    }
+
+    fn write_back(&mut self) {
+        if !self.disk.borrow().crashed {
+            self.inner.write_back()
+        }
+    }
+}
+
+// fails at the fault point by panicking; it is crashed from then on and every
+// call panics, and the db file gets no more writes, not even when it is dropped
+pub struct FaultyPersist {
+    inner: KV,
+    fault: Fault,
+    flushes: u64,
+    disk: Rc<RefCell<Disk>>,
+}
+
+impl FaultyPersist {
+    pub fn open(path: &str, storage: Storage, fault: Fault) -> Result<Self, String> {
+        let disk = Rc::new(RefCell::new(Disk::default()));
+        let pages_disk = disk.clone();
+        let inner = KV::open_wrapped(String::from(path), storage, |pages| {
+            Box::new(FaultyPages { inner: pages, disk: pages_disk })
+        })?;
+        // counting from the writes after the open
+        if let Fault::Write(n) = fault {
+            let mut disk = disk.borrow_mut();
+            disk.writes = 0;
+            disk.write_fault = Some(n);
+        }
+        Ok(FaultyPersist {
+            inner,
+            fault,
+            flushes: 0,
+            disk,
+        })
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.disk.borrow().crashed
+    }
+
+    fn crash(&mut self, at: &str) -> ! {
+        self.disk.borrow_mut().crashed = true;
+        panic!("simulated crash at {}", at);
+    }
+
+    fn alive(&self) {
+        assert!(!self.crashed(), "persist used after a crash");
+    }
+
+    // a call that writes pages, a write fault panics within it
+    fn writing<R>(&mut self, f: impl FnOnce(&mut KV) -> R) -> R {
+        self.alive();
+        match catch_unwind(AssertUnwindSafe(|| f(&mut self.inner))) {
+            Ok(r) => r,
+            Err(e) => {
+                self.disk.borrow_mut().crashed = true;
+                resume_unwind(e)
+            }
+        }
+    }
+}
+
+impl Persist for FaultyPersist {
+    fn get_node(&self, ptr: u64) -> BNode {
+        self.alive();
+        self.inner.get_node(ptr)
+    }
+
+    fn new_node(&mut self, node: &BNode) -> u64 {
+        self.alive();
+        self.inner.new_node(node)
+    }
+
+    fn del_node(&mut self, ptr: u64) {
+        self.alive();
+        self.inner.del_node(ptr)
+    }
+
+    fn len(&self) -> usize {
+        self.alive();
+        self.inner.len()
+    }
+
+    fn used(&self) -> u64 {
+        self.alive();
+        self.inner.used()
+    }
+
+    fn free_pages(&self) -> Vec<u64> {
+        self.alive();
+        self.inner.free_pages()
+    }
+
+    fn get_root(&self) -> u64 {
+        self.alive();
+        self.inner.get_root()
+    }
+
+    fn set_root(&mut self, root: u64) {
+        self.alive();
+        self.inner.set_root(root)
+    }
+
+    fn flush(&mut self) {
+        self.alive();
+        self.flushes += 1;
+        match &self.fault {
+            Fault::Flush(n) if *n == self.flushes => {
+                self.crash(&format!("flush {}", n));
+            }
+            Fault::TornFlush(n) if *n == self.flushes => {
+                let n = *n;
+                self.disk.borrow_mut().torn = true;
+                self.inner.flush();
+                self.crash(&format!("torn flush {}", n));
+            }
+            _ => self.writing(|inner| inner.flush()),
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn truncate(&mut self) {
+        self.writing(|inner| inner.truncate())
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot, String> {
+        self.alive();
+        self.inner.snapshot()
+    }
+
+    fn versions(&self) -> Vec<Version> {
+        self.inner.versions()
+    }
+
+    fn set_retain(&mut self, retain: usize) -> Result<(), String> {
+        self.writing(|inner| inner.set_retain(retain))
+    }
+
+    fn tag(&mut self, name: &str) -> Result<u64, String> {
+        self.writing(|inner| inner.tag(name))
+    }
+
+    fn untag(&mut self, name: &str) -> Result<(), String> {
+        self.writing(|inner| inner.untag(name))
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.inner.set_durability(durability)
+    }
+
+    fn durability(&self) -> Durability {
+        self.inner.durability()
+    }
+
+    fn sync(&mut self) {
+        self.writing(|inner| inner.sync())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::remove_file;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    use crate::b_tree::BTree;
+    use crate::b_tree::tests::{model_key, model_val, Rng};
+    use crate::kv::{KV, Storage};
+
+    use super::*;
+
+    type Op = (Vec<u8>, Option<Vec<u8>>);
+
+    fn workload(seed: u64, n_ops: usize) -> Vec<Op> {
+        let mut rng = Rng(seed);
+        (0..n_ops).map(|_| {
+            let key = model_key(rng.below(24));
+            if rng.below(3) == 0 {
+                (key, None)
+            } else {
+                (key, Some(model_val(&mut rng)))
+            }
+        }).collect()
+    }
+
+    // the committed state after each prefix of the workload
+    fn states(ops: &[Op]) -> Vec<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let mut model = BTreeMap::new();
+        let mut states = vec![model.clone()];
+        for (key, val) in ops {
+            match val {
+                Some(val) => model.insert(key.clone(), val.clone()),
+                None => model.remove(key),
+            };
+            states.push(model.clone());
+        }
+        states
+    }
+
+    fn replay(tree: &mut BTree, ops: &[Op], done: &mut usize) {
+        for (key, val) in ops {
+            match val {
+                Some(val) => tree.insert(key, val),
+                None => {
+                    tree.delete(key);
+                }
+            }
+            *done += 1;
+        }
+    }
+
+    // crash at every fault point in turn until the workload gets through
+    fn crash_everywhere(path: &str, ops: &[Op], fault: impl Fn(u64) -> Fault) -> u64 {
+        crash_everywhere_on(path, Storage::Mmap, ops, fault)
+    }
+
+    fn crash_everywhere_on(path: &str, storage: Storage, ops: &[Op], fault: impl Fn(u64) -> Fault) -> u64 {
+        let states = states(ops);
+        for n in 1.. {
+            let _ = remove_file(path);
+            let mut tree = BTree::new(Box::new(FaultyPersist::open(path, storage, fault(n)).unwrap()));
+            let mut done = 0;
+            let r = catch_unwind(AssertUnwindSafe(|| replay(&mut tree, ops, &mut done)));
+            drop(tree);
+
+            let tree = BTree::new(Box::new(KV::open(String::from(path), storage).unwrap()));
+            assert_eq!(tree.check(), Ok(()), "fault {}", n);
+            let got: BTreeMap<_, _> = tree.scan(&[0x00]).collect();
+            assert!(got == states[done], "fault {} after {} ops", n, done);
+            if r.is_ok() {
+                assert_eq!(done, ops.len());
+                return n - 1;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_crash_at_write() {
+        let ops = workload(7, 60);
+        let points = crash_everywhere("test_crash_write.db", &ops, Fault::Write);
+        assert!(points as usize > ops.len());
+    }
+
+    #[test]
+    fn test_crash_at_flush() {
+        let ops = workload(11, 60);
+        crash_everywhere("test_crash_flush.db", &ops, Fault::Flush);
+    }
+
+    #[test]
+    fn test_crash_torn_flush() {
+        let ops = workload(13, 60);
+        crash_everywhere("test_crash_torn.db", &ops, Fault::TornFlush);
+    }
+
+    #[test]
+    fn test_crash_pool() {
+        let ops = workload(17, 60);
+        crash_everywhere_on("test_crash_pool.db", Storage::Pool(4), &ops, Fault::Write);
+        crash_everywhere_on("test_crash_pool.db", Storage::Pool(4), &ops, Fault::TornFlush);
+    }
+
+    #[test]
+    fn test_crashed() {
+        let _ = remove_file("test_crashed.db");
+        let mut persist = FaultyPersist::open("test_crashed.db", Storage::Mmap, Fault::Flush(1)).unwrap();
+        let r = catch_unwind(AssertUnwindSafe(|| persist.flush()));
+        assert!(r.is_err());
+        assert!(persist.crashed());
+        assert!(catch_unwind(AssertUnwindSafe(|| persist.get_root())).is_err());
+    }
+
+    #[test]
+    fn test_crash_unsynced() {
+        let _ = remove_file("test_crash_unsynced.db");
+        let persist = FaultyPersist::open("test_crash_unsynced.db", Storage::Mmap, Fault::Flush(3)).unwrap();
+        let mut tree = BTree::new(Box::new(persist));
+        tree.set_durability(Durability::SyncAfter(std::time::Duration::from_secs(3600)));
+        tree.insert(b"a", b"1");
+        tree.sync();
+        tree.insert(b"b", b"1");
+        let r = catch_unwind(AssertUnwindSafe(|| tree.insert(b"c", b"1")));
+        assert!(r.is_err());
+        // the commits since the sync are lost, dropping does not sync them
+        drop(tree);
+        let tree = BTree::new(Box::new(KV::new(String::from("test_crash_unsynced.db")).unwrap()));
+        assert_eq!(tree.check(), Ok(()));
+        assert!(tree.scan(&[0x00]).eq([(b"a".to_vec(), b"1".to_vec())]));
+    }
+}
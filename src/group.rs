@@ -0,0 +1,261 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::b_tree::BTree;
+use crate::kv::KV;
+
+// a put, or a delete when the val is None
+type Op = (Vec<u8>, Option<Vec<u8>>);
+
+// the ops of one commit, applied in order
+#[derive(Default)]
+pub struct Txn {
+    ops: Vec<Op>,
+}
+
+impl Txn {
+    pub fn new() -> Txn {
+        Txn::default()
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
+        self.ops.push((key.to_vec(), Some(val.to_vec())));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push((key.to_vec(), None));
+    }
+}
+
+enum Request {
+    Commit(Vec<Op>, Sender<()>),
+    Get(Vec<u8>, Sender<Option<Vec<u8>>>),
+}
+
+// joins the writer once the last handle is gone
+struct Writer {
+    thread: Option<JoinHandle<()>>,
+    // flushes so far, each one commits a group of txns
+    groups: Arc<AtomicU64>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// a db many threads can write to: one writer thread owns the tree and applies the txns
+// in the order they arrive; the txns queued while a flush runs go in together with the
+// next flush, so concurrent writers share its syncs
+#[derive(Clone)]
+pub struct GroupDB {
+    // dropped before the writer, so the last handle ends the writer thread
+    sender: Sender<Request>,
+    writer: Arc<Writer>,
+    max_key_size: usize,
+    max_val_size: usize,
+}
+
+impl GroupDB {
+    pub fn open(path: &str) -> Result<GroupDB, String> {
+        let (sender, requests) = channel();
+        let (opened, open) = channel();
+        let groups = Arc::new(AtomicU64::new(0));
+        let counter = groups.clone();
+        let path = String::from(path);
+        // the tree is not Send, it is made and used on the writer thread only
+        let thread = thread::spawn(move || {
+            let mut tree = match KV::new(path) {
+                Ok(kv) => BTree::new(Box::new(kv)),
+                Err(e) => return opened.send(Err(e)).unwrap(),
+            };
+            opened.send(Ok((tree.max_key_size(), tree.max_val_size()))).unwrap();
+            serve(&mut tree, &requests, &counter);
+        });
+        let (max_key_size, max_val_size) = open.recv().map_err(|_| String::from("the writer failed to start"))??;
+        Ok(GroupDB {
+            sender,
+            writer: Arc::new(Writer { thread: Some(thread), groups }),
+            max_key_size,
+            max_val_size,
+        })
+    }
+
+    pub fn groups(&self) -> u64 {
+        self.writer.groups.load(Ordering::SeqCst)
+    }
+
+    // returns once the txn is on disk, in the same flush as the txns queued with it
+    pub fn commit(&self, txn: Txn) -> Result<(), String> {
+        for (key, val) in &txn.ops {
+            if key.is_empty() || key.len() > self.max_key_size {
+                return Err(format!("key of {} bytes", key.len()));
+            }
+            if let Some(val) = val.as_ref().filter(|v| v.len() > self.max_val_size) {
+                return Err(format!("val of {} bytes", val.len()));
+            }
+        }
+        let (done, wait) = channel();
+        self.send(Request::Commit(txn.ops, done))?;
+        wait.recv().map_err(|_| String::from("the writer is gone"))
+    }
+
+    // sees the committed txns only
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if key.is_empty() || key.len() > self.max_key_size {
+            return Err(format!("key of {} bytes", key.len()));
+        }
+        let (done, wait) = channel();
+        self.send(Request::Get(key.to_vec(), done))?;
+        wait.recv().map_err(|_| String::from("the writer is gone"))
+    }
+
+    fn send(&self, request: Request) -> Result<(), String> {
+        self.sender.send(request).map_err(|_| String::from("the writer is gone"))
+    }
+}
+
+// until every handle is dropped: take what is queued, apply the txns in one tree txn,
+// flush once, then let their callers go and answer the reads
+fn serve(tree: &mut BTree, requests: &Receiver<Request>, groups: &AtomicU64) {
+    while let Ok(first) = requests.recv() {
+        let (mut commits, mut gets) = (Vec::new(), Vec::new());
+        for request in std::iter::once(first).chain(requests.try_iter()) {
+            match request {
+                Request::Commit(ops, done) => commits.push((ops, done)),
+                Request::Get(key, done) => gets.push((key, done)),
+            }
+        }
+        if !commits.is_empty() {
+            tree.begin();
+            for (key, val) in commits.iter().flat_map(|(ops, _)| ops) {
+                match val {
+                    Some(val) => tree.insert(key, val),
+                    None => {
+                        tree.delete(key);
+                    }
+                }
+            }
+            tree.commit();
+            groups.fetch_add(1, Ordering::SeqCst);
+        }
+        // a caller that gave up waiting is no matter
+        for (_, done) in commits {
+            let _ = done.send(());
+        }
+        for (key, done) in gets {
+            let _ = done.send(tree.get(&key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+
+    fn txn(key: &[u8], val: &[u8]) -> Txn {
+        let mut txn = Txn::new();
+        txn.insert(key, val);
+        txn
+    }
+
+    #[test]
+    fn test_group_serve() {
+        let _ = remove_file("test_group_serve.db");
+        let mut tree = BTree::new(Box::new(KV::new(String::from("test_group_serve.db")).unwrap()));
+        let (sender, requests) = channel();
+        let mut waits = Vec::new();
+        // queued before the writer looks, they share one flush
+        for i in 0..100u32 {
+            let (done, wait) = channel();
+            let mut txn = txn(&i.to_be_bytes(), &[0xac; 100]);
+            txn.delete(&(i / 2).to_be_bytes());
+            sender.send(Request::Commit(txn.ops, done)).unwrap();
+            waits.push(wait);
+        }
+        let (done, read) = channel();
+        sender.send(Request::Get(99u32.to_be_bytes().to_vec(), done)).unwrap();
+        drop(sender);
+        let groups = AtomicU64::new(0);
+        serve(&mut tree, &requests, &groups);
+        assert_eq!(groups.load(Ordering::SeqCst), 1);
+        assert!(waits.iter().all(|w| w.recv().is_ok()));
+        assert_eq!(read.recv().unwrap(), Some(vec![0xac; 100]));
+        // in order: a key deleted by a later txn is gone
+        assert_eq!(tree.get(&49u32.to_be_bytes()), None);
+        assert_eq!(tree.scan(&[0x00]).count(), 50);
+    }
+
+    // a db whose writer waits for the gate before it takes any request
+    fn open_gated(path: &str) -> (GroupDB, Sender<()>) {
+        let _ = remove_file(path);
+        let (sender, requests) = channel();
+        let (gate, wait) = channel();
+        let groups = Arc::new(AtomicU64::new(0));
+        let counter = groups.clone();
+        let (opened, open) = channel();
+        let path = String::from(path);
+        let thread = thread::spawn(move || {
+            let mut tree = BTree::new(Box::new(KV::new(path).unwrap()));
+            opened.send((tree.max_key_size(), tree.max_val_size())).unwrap();
+            wait.recv().unwrap();
+            serve(&mut tree, &requests, &counter);
+        });
+        let (max_key_size, max_val_size) = open.recv().unwrap();
+        let writer = Arc::new(Writer { thread: Some(thread), groups });
+        (GroupDB { sender, writer, max_key_size, max_val_size }, gate)
+    }
+
+    #[test]
+    fn test_group_commit() {
+        // the txns of all threads queue while the writer is held, one flush takes them all
+        let (db, gate) = open_gated("test_group_commit.db");
+        let writers: Vec<_> = (0..8u32).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                (0..50u32).map(|i| {
+                    let (done, wait) = channel();
+                    let txn = txn(&(t * 1000 + i).to_be_bytes(), &i.to_be_bytes());
+                    db.send(Request::Commit(txn.ops, done)).unwrap();
+                    wait
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        let waits: Vec<_> = writers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+        gate.send(()).unwrap();
+        assert!(waits.iter().all(|w| w.recv().is_ok()));
+        assert_eq!(db.groups(), 1);
+
+        // commits from many threads, each waits for its own
+        let writers: Vec<_> = (0..8u32).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..50u32 {
+                    let key = (t * 1000 + i).to_be_bytes();
+                    db.commit(txn(&key, &[i as u8; 10])).unwrap();
+                    // durable and visible once commit returns
+                    assert_eq!(db.get(&key).unwrap(), Some(vec![i as u8; 10]));
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(db.commit(txn(&[0xac; 5000], b"")).is_err());
+        assert!(db.get(&[]).is_err());
+        assert!(db.get(&[0xac; 5000]).is_err());
+        drop(db);
+
+        // the writer is done with the file
+        let tree = BTree::new(Box::new(KV::new(String::from("test_group_commit.db")).unwrap()));
+        assert_eq!(tree.scan(&[0x00]).count(), 400);
+        assert_eq!(tree.check(), Ok(()));
+    }
+}
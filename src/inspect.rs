@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+
+use crate::b_node::{BNode, BType};
+use crate::common::{BTREE_PAGE_SIZE, check_page_size};
+use crate::kv::{DB_SIG, META_FREE, META_PAGE_SIZE, META_ROOT, META_SIZE, META_USED, META_VERSION};
+use crate::kv::history::History;
+use crate::little_endian::LittleEndian;
+
+// read-only view of a db file, no mmap and no meta checks,
+// so it still works on files KV::new refuses to open
+pub struct Inspector {
+    file: File,
+    page_size: usize,
+    n_pages: u64,
+}
+
+impl Inspector {
+    pub fn open(path: &str) -> Result<Inspector, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+        // the default when the recorded page size is missing or bogus
+        let mut meta = [0; META_SIZE];
+        let page_size = match file.read_exact_at(&mut meta, 0) {
+            Ok(()) => u64::from_le_bytes(meta[META_PAGE_SIZE..META_SIZE].try_into().unwrap()) as usize,
+            Err(_) => 0,
+        };
+        let page_size = if check_page_size(page_size).is_ok() { page_size } else { BTREE_PAGE_SIZE };
+        Ok(Inspector {
+            file,
+            page_size,
+            n_pages: len / page_size as u64,
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn page(&self, ptr: u64) -> Result<Vec<u8>, String> {
+        if ptr >= self.n_pages {
+            return Err(format!("page {} beyond end of file ({} pages)", ptr, self.n_pages));
+        }
+        let mut data = vec![0; self.page_size];
+        self.file.read_exact_at(&mut data, ptr * self.page_size as u64).map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    // meta info: (sig, root, used, free)
+    pub fn meta(&self) -> Result<(Vec<u8>, u64, u64, u64), String> {
+        let master = BNode::new_with_data(self.page(0)?);
+        let sig = master.get_bytes(0, META_ROOT as u16).to_vec();
+        Ok((sig, master.read_u64(META_ROOT), master.read_u64(META_USED), master.read_u64(META_FREE)))
+    }
+
+    pub fn dump_meta(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (sig, root, used, free) = match self.meta() {
+            Ok(m) => m,
+            Err(e) => return writeln!(out, "meta: {}", e),
+        };
+        let sig_ok = if sig == DB_SIG.as_bytes() { "ok" } else { "BAD" };
+        writeln!(out, "signature: {} ({})", fmt_bytes(&sig), sig_ok)?;
+        writeln!(out, "root: {}", root)?;
+        writeln!(out, "used: {}", used)?;
+        writeln!(out, "free list: {}", free)?;
+        writeln!(out, "page size: {}", self.page_size)?;
+        writeln!(out, "file pages: {}", self.n_pages)?;
+        let master = self.page(0).unwrap();
+        writeln!(out, "version: {}", BNode::new_with_data(master.clone()).read_u64(META_VERSION))?;
+        match History::decode(&master) {
+            Ok(history) => {
+                for v in history.versions() {
+                    writeln!(out, "kept: version {} root {} {}", v.version, v.root, v.name.unwrap_or_default())?;
+                }
+                Ok(())
+            }
+            Err(e) => writeln!(out, "history: {}", e),
+        }
+    }
+
+    pub fn dump_tree(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (_, root, used, _) = match self.meta() {
+            Ok(m) => m,
+            Err(e) => return writeln!(out, "meta: {}", e),
+        };
+        if root == 0 {
+            return writeln!(out, "empty tree");
+        }
+        let mut visited = HashSet::new();
+        self.dump_node(out, root, used, 0, &mut visited)
+    }
+
+    fn dump_node(&self, out: &mut dyn Write, ptr: u64, used: u64, depth: usize,
+                 visited: &mut HashSet<u64>) -> io::Result<()> {
+        let indent = "  ".repeat(depth);
+        if ptr == 0 || ptr >= used {
+            return writeln!(out, "{}[{}] pointer outside used pages (used {})", indent, ptr, used);
+        }
+        if !visited.insert(ptr) {
+            return writeln!(out, "{}[{}] already visited", indent, ptr);
+        }
+        let node = match self.page(ptr) {
+            Ok(data) => BNode::new_with_data(data),
+            Err(e) => return writeln!(out, "{}[{}] {}", indent, ptr, e),
+        };
+        if let Err(e) = node.check_layout() {
+            return writeln!(out, "{}[{}] corrupt: {}", indent, ptr, e);
+        }
+
+        let n_keys = node.n_keys();
+        let fill = node.n_bytes() as f64 * 100.0 / self.page_size as f64;
+        let range = if n_keys == 0 {
+            String::from("-")
+        } else {
+            format!("{} .. {}", fmt_bytes(node.get_key(0)), fmt_bytes(node.get_key(n_keys - 1)))
+        };
+        match node.n_type() {
+            BType::LEAF => {
+                writeln!(out, "{}[{}] leaf keys={} fill={:.1}% range={}", indent, ptr, n_keys, fill, range)
+            }
+            BType::Node => {
+                let ptrs: Vec<u64> = (0..n_keys).map(|i| node.get_ptr(i)).collect();
+                writeln!(out, "{}[{}] node keys={} fill={:.1}% range={} kids={:?}",
+                         indent, ptr, n_keys, fill, range, ptrs)?;
+                for kid in ptrs {
+                    self.dump_node(out, kid, used, depth + 1, visited)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn dump_page(&self, out: &mut dyn Write, ptr: u64) -> io::Result<()> {
+        let data = match self.page(ptr) {
+            Ok(data) => data,
+            Err(e) => return writeln!(out, "{}", e),
+        };
+        writeln!(out, "page {} @ offset {:#x}", ptr, ptr * self.page_size as u64)?;
+        for (i, line) in data.chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            writeln!(out, "{:08x}  {}  |{}|", i * 16, hex.join(" "), ascii)?;
+        }
+        Ok(())
+    }
+}
+
+// printable keys as text, the rest as hex
+fn fmt_bytes(data: &[u8]) -> String {
+    const MAX: usize = 16;
+    if data.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        return if data.len() > MAX {
+            format!("{:?}..({} bytes)", String::from_utf8_lossy(&data[..MAX]), data.len())
+        } else {
+            format!("{:?}", String::from_utf8_lossy(data))
+        };
+    }
+    let hex: String = data.iter().take(MAX).map(|b| format!("{:02x}", b)).collect();
+    if data.len() > MAX {
+        format!("0x{}..({} bytes)", hex, data.len())
+    } else {
+        format!("0x{}", hex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+
+    fn leaf(keys: &[&[u8]]) -> BNode {
+        let mut node = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        node.set_header(BType::LEAF, keys.len() as u16);
+        for (i, key) in keys.iter().enumerate() {
+            node.insert_kv(i as u16, 0, key, &[0xac]);
+        }
+        node
+    }
+
+    // meta | root node | leaf 1 | leaf 2
+    fn init(path: &str) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap();
+        let mut page = DB_SIG.as_bytes().to_vec();
+        page.extend_from_slice(&1u64.to_le_bytes());
+        page.extend_from_slice(&4u64.to_le_bytes());
+        page.resize(BTREE_PAGE_SIZE, 0);
+
+        let left = leaf(&[b"", b"a"]);
+        let right = leaf(&[b"m", &[0xff, 0x00]]);
+        let mut root = BNode::new_with_cap(BTREE_PAGE_SIZE);
+        root.set_header(BType::Node, 2);
+        root.insert_kv(0, 2, b"", &[]);
+        root.insert_kv(1, 3, b"m", &[]);
+
+        file.write_all_at(&page, 0).unwrap();
+        for (i, node) in [root, left, right].iter().enumerate() {
+            let offset = (i + 1) * BTREE_PAGE_SIZE;
+            file.write_all_at(node.get_bytes(0, BTREE_PAGE_SIZE as u16), offset as u64).unwrap();
+        }
+    }
+
+    fn output(f: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_meta() {
+        init("test_inspect_meta.db");
+        let ins = Inspector::open("test_inspect_meta.db").unwrap();
+        let out = output(|w| ins.dump_meta(w));
+        assert!(out.contains("signature: \"BuildYourOwnDB05\" (ok)"));
+        assert!(out.contains("root: 1"));
+        assert!(out.contains("used: 4"));
+        assert!(out.contains("free list: 0"));
+        assert!(out.contains("file pages: 4"));
+    }
+
+    #[test]
+    fn test_tree() {
+        init("test_inspect_tree.db");
+        let ins = Inspector::open("test_inspect_tree.db").unwrap();
+        let out = output(|w| ins.dump_tree(w));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("[1] node keys=2"));
+        assert!(lines[0].ends_with("range=\"\" .. \"m\" kids=[2, 3]"));
+        assert!(lines[1].starts_with("  [2] leaf keys=2"));
+        assert!(lines[2].ends_with("range=\"m\" .. 0xff00"));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        init("test_inspect_corrupt.db");
+        let file = OpenOptions::new().write(true).open("test_inspect_corrupt.db").unwrap();
+        file.write_all_at(&[0x07], 3 * BTREE_PAGE_SIZE as u64).unwrap();
+        let ins = Inspector::open("test_inspect_corrupt.db").unwrap();
+        let out = output(|w| ins.dump_tree(w));
+        assert!(out.contains("[3] corrupt: bad node type 7"));
+    }
+
+    #[test]
+    fn test_page() {
+        init("test_inspect_page.db");
+        let ins = Inspector::open("test_inspect_page.db").unwrap();
+        let out = output(|w| ins.dump_page(w, 0));
+        assert!(out.starts_with("page 0 @ offset 0x0"));
+        assert!(out.contains("00000000  42 75 69 6c 64 59 6f 75 72 4f 77 6e 44 42 30 35  |BuildYourOwnDB05|"));
+        assert_eq!(out.lines().count(), 1 + BTREE_PAGE_SIZE / 16);
+        assert!(output(|w| ins.dump_page(w, 9)).contains("beyond end of file"));
+    }
+}
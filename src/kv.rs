@@ -1,151 +1,507 @@
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::os::fd::AsFd;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::b_node::BNode;
-use crate::common::{BTREE_PAGE_SIZE, Persist, SYS_PAGE_SIZE};
-use crate::kv::file_map::FileMap;
+use crate::b_node::{BNode, BType};
+use crate::common::{BTREE_PAGE_SIZE, check_page_size, Persist};
+use crate::kv::backup::Snapshot;
+use crate::kv::file_map::FileMaps;
+use crate::kv::free_list::FreeList;
+use crate::kv::history::{History, Version};
+use crate::kv::pool::Pool;
+use crate::kv::syncer::Syncer;
 use crate::little_endian::LittleEndian;
 
-mod file_map;
+pub mod backup;
+pub mod file_map;
+pub mod free_list;
+pub mod history;
+pub mod pool;
+pub mod syncer;
 
-const DB_SIG: &str = "BuildYourOwnDB05";
+pub const DB_SIG: &str = "BuildYourOwnDB05";
+// meta page layout: | sig | root | used | free | page size | version | history |
+pub const META_ROOT: usize = 16;
+pub const META_USED: usize = 24;
+pub const META_FREE: usize = 32;
+// 0 in files older than the field, they use the default
+pub const META_PAGE_SIZE: usize = 40;
+pub const META_SIZE: usize = 48;
+// commits so far, 0 in older files
+pub const META_VERSION: usize = 48;
+pub const META_HISTORY: usize = 56;
+// the file is mapped in chunks of doubling size: | base | base | 2 base | 4 base | ...
+pub const MAP_BASE: usize = 64 << 20;
 
-struct KV {
+// how pages reach the file, chosen at open time
+#[derive(Clone, Copy, Debug)]
+pub enum Storage {
+    Mmap,
+    // pread/pwrite through a pool of this many pages
+    Pool(usize),
+}
+
+// the pages of the file in memory, a write reaches the file by a flush
+pub trait Pages {
+    fn read(&self, ptr: u64) -> Vec<u8>;
+    fn write(&mut self, ptr: u64, data: &[u8]);
+    // the file is now at least `size` bytes
+    fn grow(&mut self, _size: usize) {}
+    // the written pages are on disk once it returns
+    fn flush(&mut self);
+    // the written pages go to the os without waiting for the disk
+    fn write_back(&mut self);
+}
+
+// how far a commit has got when flush returns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    // the pages are synced, then the meta page: the commit survives a crash of the
+    // process or the os
+    Sync,
+    // the pages go out without waiting, the meta page only at a sync: by a thread of the
+    // db once this long has passed since the last sync, by a commit after that, by sync(),
+    // or when the db is closed. a crash of the process or the os loses the commits since
+    // the last sync but leaves the db as it was then, the pages that state uses are not
+    // reused before the next sync
+    SyncAfter(Duration),
+    // nothing is synced, the meta page is written on every commit: a crash of the process
+    // loses nothing, a crash of the os can lose any commit and break the file; for scratch data
+    None,
+}
+
+pub struct KV {
     path: String,
 
     file: File,
-    map_size: usize,
-    // file map
-    file_maps: Vec<FileMap>,
+    file_size: usize,
+    page_size: usize,
+    // the file maps or a pool
+    pages: Box<dyn Pages>,
     flushed: u64,
+    // next page to append, past the pages of this txn
+    tail: u64,
     // temp BNode, in mem, no disk
-    temp: Vec<BNode>,
+    temp: HashMap<u64, BNode>,
+    free: FreeList,
+    // live snapshots, the free list holds freed pages while there are any
+    pins: Arc<AtomicUsize>,
+    // the committed roots kept readable
+    history: History,
+    version: u64,
+    durability: Durability,
+    // commits since the meta page was last synced, with SyncAfter
+    unsynced: bool,
+    syncer: Syncer,
 
     root: u64,
 }
 
 impl Persist for KV {
     fn get_node(&self, ptr: u64) -> BNode {
-        let row = ptr as usize / (*SYS_PAGE_SIZE / BTREE_PAGE_SIZE);
-        let col = ptr as usize % (*SYS_PAGE_SIZE / BTREE_PAGE_SIZE);
-        assert!(row < self.file_maps.len());
-        let x = self.file_maps[row].read(col);
-        BNode::new_with_data(x.to_vec())
+        if let Some(node) = self.temp.get(&ptr) {
+            return node.clone();
+        }
+        BNode::new_with_data(self.read_page(ptr))
     }
 
     fn new_node(&mut self, node: &BNode) -> u64 {
-        let ptr = self.flushed + self.temp.len() as u64;
-        self.temp.push(node.clone());
+        let ptr = match self.free.pop() {
+            Some(ptr) => ptr,
+            None => {
+                self.tail += 1;
+                self.tail - 1
+            }
+        };
+        self.temp.insert(ptr, node.clone());
         ptr
     }
 
     fn del_node(&mut self, ptr: u64) {
-        // Todo
+        if self.temp.remove(&ptr).is_some() {
+            self.free.reuse(ptr);
+        } else {
+            self.free.push(ptr);
+        }
     }
 
     fn len(&self) -> usize {
         self.flushed as usize - 1
     }
 
+    fn used(&self) -> u64 {
+        self.flushed
+    }
+
+    fn free_pages(&self) -> Vec<u64> {
+        self.free.pages()
+    }
+
     fn get_root(&self) -> u64 {
         self.root
     }
 
     fn set_root(&mut self, root: u64) {
         self.root = root;
-        self.file_maps[0].write_u64(16, root);
     }
 
+    // pages first, the meta page only once they are on disk
     fn flush(&mut self) {
-        self.write_temp_to_map();
-        self.flush_map();
+        self.catch_up();
+        let sync = match self.durability {
+            Durability::SyncAfter(interval) => self.syncer.last_sync().1.elapsed() >= interval,
+            _ => true,
+        };
+        self.write_temp_to_map(!sync);
+        match self.durability {
+            Durability::None => {
+                self.syncer.cancel();
+                self.write_meta();
+                self.write_back();
+            }
+            Durability::SyncAfter(interval) if !sync => {
+                self.write_back();
+                self.unsynced = true;
+                let meta = self.meta();
+                self.syncer.defer(&self.file, self.version, meta, interval);
+            }
+            _ => self.sync_meta(),
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // the file is cut only once the meta page without the tail is on disk
+    fn truncate(&mut self) {
+        assert!(self.temp.is_empty());
+        // moves the free list storage into the lowest free pages
+        self.flush();
+        let end = self.free.tail_from(self.flushed);
+        if end == self.flushed {
+            return;
+        }
+        self.free.truncate(end);
+        self.tail = end;
+        self.flush();
+        // the meta page of a deferred or unsynced flush still has the old tail
+        self.sync_meta();
+        let size = self.flushed as usize * self.page_size;
+        self.file.set_len(size as u64).unwrap();
+        self.file.sync_all().unwrap();
+        self.file_size = size;
+    }
+
+    // the root set last is the committed one, set_root only comes with a flush
+    fn snapshot(&mut self) -> Result<Snapshot, String> {
+        Ok(Snapshot::new(&self.path, self.page_size, self.root, self.flushed, self.meta(), &self.pins))
+    }
+
+    fn versions(&self) -> Vec<Version> {
+        self.history.versions()
+    }
+
+    // the history only goes to the meta page, so it can change within a txn
+    fn set_retain(&mut self, retain: usize) -> Result<(), String> {
+        self.history.set_retain(retain, self.page_size)?;
+        self.sync_meta();
+        Ok(())
+    }
+
+    fn tag(&mut self, name: &str) -> Result<u64, String> {
+        self.history.tag(name, self.version, self.root, self.page_size)?;
+        self.sync_meta();
+        Ok(self.version)
+    }
+
+    fn untag(&mut self, name: &str) -> Result<(), String> {
+        self.history.untag(name)?;
+        self.sync_meta();
+        Ok(())
+    }
+
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    fn sync(&mut self) {
+        self.catch_up();
+        if self.unsynced {
+            self.sync_meta();
+        }
+    }
+}
+
+// unsynced commits reach the disk
+impl Drop for KV {
+    fn drop(&mut self) {
+        self.sync();
     }
 }
 
 impl KV {
     pub fn new(path: String) -> Result<KV, String> {
+        Self::open(path, Storage::Mmap)
+    }
+
+    // a new db gets the default page size, an existing one keeps its own
+    pub fn open(path: String, storage: Storage) -> Result<KV, String> {
+        Self::open_inner(path, storage, None, |pages| pages)
+    }
+
+    // fails if an existing db uses another page size
+    pub fn open_with_page_size(path: String, storage: Storage, page_size: usize) -> Result<KV, String> {
+        check_page_size(page_size)?;
+        Self::open_inner(path, storage, Some(page_size), |pages| pages)
+    }
+
+    // the pages go through `wrap`, to see or change what reaches the file
+    pub fn open_wrapped(path: String, storage: Storage, wrap: impl FnOnce(Box<dyn Pages>) -> Box<dyn Pages>) -> Result<KV, String> {
+        Self::open_inner(path, storage, None, wrap)
+    }
+
+    fn open_inner(path: String, storage: Storage, want: Option<usize>,
+                  wrap: impl FnOnce(Box<dyn Pages>) -> Box<dyn Pages>) -> Result<KV, String> {
         // file
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(&path).unwrap();
+            .truncate(false)
+            .open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let file_size = file.metadata().map_err(|e| e.to_string())?.len() as usize;
 
-        // file_map
-        let n_sys_pages = file.metadata().unwrap().len() as usize / *SYS_PAGE_SIZE + 1;
-        let mut file_maps = Vec::new();
-        for i in 0..n_sys_pages {
-            file_maps.push(FileMap::new(&file, *SYS_PAGE_SIZE, i * (*SYS_PAGE_SIZE)));
+        // page size, needed before any page can be read
+        let page_size = if file_size == 0 {
+            want.unwrap_or(BTREE_PAGE_SIZE)
+        } else {
+            if file_size < META_SIZE {
+                return Err(format!("file of {} bytes has no meta page", file_size));
+            }
+            let mut meta = [0; META_SIZE];
+            file.read_exact_at(&mut meta, 0).map_err(|e| e.to_string())?;
+            match u64::from_le_bytes(meta[META_PAGE_SIZE..META_SIZE].try_into().unwrap()) as usize {
+                0 => BTREE_PAGE_SIZE,
+                n => n,
+            }
+        };
+        check_page_size(page_size)?;
+        if let Some(want) = want {
+            if want != page_size {
+                return Err(format!("db uses page size {}, not {}", page_size, want));
+            }
         }
 
-        let master = file_maps[0].read(0);
-        let sig = &master[..16];
-        let root = file_maps[0].read_u64(16);
-        let used = file_maps[0].read_u64(24);
-
-        if sig != DB_SIG.as_bytes() {
-            return Err(String::from("db sgi err"));
-        }
+        let pages: Box<dyn Pages> = match storage {
+            Storage::Mmap => Box::new(FileMaps::new(file.try_clone().unwrap(), page_size)),
+            Storage::Pool(cap) => Box::new(Pool::new(file.try_clone().unwrap(), cap, page_size)),
+        };
 
-        let kv = KV {
+        let mut kv = KV {
             path,
             file,
-            map_size: n_sys_pages * (*SYS_PAGE_SIZE),
-            file_maps,
-            temp: Vec::new(),
-            root,
-            flushed: used,
+            file_size,
+            page_size,
+            pages: wrap(pages),
+            temp: HashMap::new(),
+            free: FreeList::new(page_size),
+            pins: Arc::new(AtomicUsize::new(0)),
+            history: History::default(),
+            version: 0,
+            durability: Durability::Sync,
+            unsynced: false,
+            syncer: Syncer::new(0),
+            root: 0,
+            flushed: 1,
+            tail: 1,
         };
+        kv.pages.grow(file_size.max(page_size));
+        if file_size == 0 {
+            kv.grow_file(1);
+            kv.write_meta();
+            kv.flush_map();
+            return Ok(kv);
+        }
+
+        let master = BNode::new_with_data(kv.read_page(0));
+        let sig = master.get_bytes(0, META_ROOT as u16);
+        if sig != DB_SIG.as_bytes() {
+            return Err(String::from("db sgi err"));
+        }
+        kv.root = master.read_u64(META_ROOT);
+        kv.flushed = master.read_u64(META_USED);
+        kv.tail = kv.flushed;
+        kv.version = master.read_u64(META_VERSION);
+        kv.syncer = Syncer::new(kv.version);
+        kv.history = History::decode(master.get_bytes(0, page_size as u16))?;
+        if kv.flushed as usize > file_size / page_size {
+            return Err(format!("used {} pages beyond end of file", kv.flushed));
+        }
+        let head = master.read_u64(META_FREE);
+        kv.free = FreeList::load(head, page_size, |ptr| {
+            if ptr >= kv.flushed {
+                return vec![0; page_size];
+            }
+            kv.read_page(ptr)
+        })?;
         Ok(kv)
     }
 
-    pub fn write_temp_to_map(&mut self) {
-        // new file map
-        let n_sys_pages = (self.flushed as usize + self.temp.len()) / ((*SYS_PAGE_SIZE) / BTREE_PAGE_SIZE);
-        if n_sys_pages > self.file_maps.len() {
-            let offset = self.file_maps.len();
-            let new_sys_pages = self.file_maps.len() - n_sys_pages;
-            for i in 0..new_sys_pages {
-                self.file_maps.push(FileMap::new(&self.file, *SYS_PAGE_SIZE, (i + offset) * (*SYS_PAGE_SIZE)));
-            }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn read_page(&self, ptr: u64) -> Vec<u8> {
+        // the map reaches past the end of the file, touching that part faults
+        assert!((ptr as usize + 1) * self.page_size <= self.file_size, "page {} beyond end of file", ptr);
+        self.pages.read(ptr)
+    }
+
+    fn write_page(&mut self, ptr: u64, data: &[u8]) {
+        self.pages.write(ptr, data);
+    }
+
+    fn grow_file(&mut self, n_pages: u64) {
+        let size = n_pages as usize * self.page_size;
+        if size > self.file_size {
+            self.file.set_len(size as u64).unwrap();
+            self.file_size = size;
         }
+        self.pages.grow(size);
+    }
+
+    // with `defer` the meta page of the commit is not going to disk yet
+    pub fn write_temp_to_map(&mut self, defer: bool) {
+        let version = self.version + 1;
+        self.history.record(version, self.root);
+        let versions = self.history.versions();
+        // only the pages freed now are looked up, the retired ones keep their oldest user
+        let first_use: HashMap<u64, u64> = self.free.freed().iter()
+            .filter_map(|ptr| self.first_use(&versions, version, *ptr).map(|first| (*ptr, first)))
+            .collect();
+        // a page is in every version from the first that uses it to the one it is freed after
+        let kept = |first: u64, freed: u64| versions.iter().any(|v| (first..freed).contains(&v.version));
+        // free list goes with the txn
+        let tail = &mut self.tail;
+        // the pages of the committed state on disk stay out of use until a later one is there
+        let pinned = self.pins.load(Ordering::SeqCst) > 0;
+        let release = !pinned && !self.unsynced;
+        let hold = pinned || self.unsynced || defer;
+        let free_pages = self.free.commit(release, hold, version, &first_use, kept, || {
+            *tail += 1;
+            *tail - 1
+        });
+
+        self.grow_file(self.tail);
 
         // copy to file
-        for _ in 0..self.temp.len() {
-            let ptr = self.flushed;
-            let row = ptr as usize / (*SYS_PAGE_SIZE / BTREE_PAGE_SIZE);
-            let col = ptr as usize % (*SYS_PAGE_SIZE / BTREE_PAGE_SIZE);
-            let node = self.temp.pop().unwrap();
-            self.file_maps[row].write(col, node.get_bytes(0, node.n_bytes()));
-            self.flushed += 1;
+        for (ptr, node) in std::mem::take(&mut self.temp) {
+            self.write_page(ptr, node.get_bytes(0, node.n_bytes()));
+        }
+        for (ptr, data) in free_pages {
+            self.write_page(ptr, &data);
         }
+        self.flushed = self.tail;
+        self.version = version;
+    }
+
+    // the oldest of the versions kept from before the commit of `freed` that has the tree page;
+    // pages are never changed in place, so a version has the page iff the path to its first key
+    // goes through it
+    fn first_use(&self, versions: &[Version], freed: u64, ptr: u64) -> Option<u64> {
+        let key = BNode::new_with_data(self.read_page(ptr)).get_key(0).to_vec();
+        versions.iter().filter(|v| v.version < freed && v.root != 0).filter(|v| {
+            let mut next = v.root;
+            loop {
+                if next == ptr {
+                    return true;
+                }
+                let node = BNode::new_with_data(self.read_page(next));
+                if node.n_type() == BType::LEAF {
+                    return false;
+                }
+                next = node.get_ptr(node.lookup_le(&key));
+            }
+        }).map(|v| v.version).min()
+    }
 
-        self.file_maps[0].write_u64(24, self.flushed);
+    fn meta(&self) -> Vec<u8> {
+        let mut meta = meta_page(self.root, self.flushed, self.free.head(), self.page_size);
+        meta.extend_from_slice(&self.version.to_le_bytes());
+        meta.extend_from_slice(&self.history.encode());
+        meta
     }
 
-    pub fn flush_map(&mut self) {
-        for mut file_map in &mut self.file_maps {
-            file_map.flush();
+    pub fn write_meta(&mut self) {
+        let meta = self.meta();
+        self.write_page(0, &meta);
+    }
+
+    // the meta page goes to disk after the pages it points to
+    fn sync_meta(&mut self) {
+        // waits for a sync of the thread in progress, it must not land after this one
+        self.syncer.cancel();
+        self.flush_map();
+        self.write_meta();
+        self.flush_map();
+        self.unsynced = false;
+        self.syncer.synced(self.version);
+    }
+
+    // the syncer thread may have synced the last commit since
+    fn catch_up(&mut self) {
+        if self.unsynced && self.syncer.last_sync().0 == self.version {
+            self.unsynced = false;
         }
     }
+
+    // the dirty pages go to the os without waiting for the disk
+    fn write_back(&mut self) {
+        self.pages.write_back();
+    }
+
+    pub fn flush_map(&mut self) {
+        self.pages.flush();
+    }
+}
+
+fn meta_page(root: u64, used: u64, free: u64, page_size: usize) -> Vec<u8> {
+    let mut meta = DB_SIG.as_bytes().to_vec();
+    meta.extend_from_slice(&root.to_le_bytes());
+    meta.extend_from_slice(&used.to_le_bytes());
+    meta.extend_from_slice(&free.to_le_bytes());
+    meta.extend_from_slice(&(page_size as u64).to_le_bytes());
+    meta
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
+    use std::collections::BTreeMap;
+    use std::fs::{OpenOptions, remove_file};
     use std::io::Write;
+    use std::time::Duration;
 
     use crate::b_node::BNode;
+    use crate::b_tree::BTree;
     use crate::common::{BTREE_PAGE_SIZE, Persist};
-    use crate::kv::{DB_SIG, KV};
+    use crate::inspect::Inspector;
+    use crate::kv::{DB_SIG, Durability, KV, MAP_BASE, Storage};
 
-    fn init() {
+    fn init(path: &str) {
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("test").unwrap();
+            .truncate(true)
+            .open(path).unwrap();
         file.set_len(BTREE_PAGE_SIZE as u64).unwrap();
         file.write_all(DB_SIG.as_bytes()).unwrap();
         file.write_all(&[0x00; 8]).unwrap();
@@ -164,16 +520,16 @@ mod tests {
 
     #[test]
     fn test_new() {
-        init();
-        let mut kv = KV::new(String::from("test")).unwrap();
+        init("test_new.db");
+        let kv = KV::new(String::from("test_new.db")).unwrap();
         assert_eq!(kv.get_root(), 0);
         assert_eq!(kv.flushed, 1);
     }
 
     #[test]
     fn test_new_node() {
-        init();
-        let mut kv = KV::new(String::from("test")).unwrap();
+        init("test_new_node.db");
+        let mut kv = KV::new(String::from("test_new_node.db")).unwrap();
         let ptr = kv.new_node(&BNode::new_with_data(node_data()));
         assert_eq!(ptr, 1);
         kv.flush();
@@ -181,10 +537,204 @@ mod tests {
         assert_eq!(kv.get_node(ptr).get_key(0), &[0xac]);
     }
 
+    #[test]
+    fn test_create() {
+        let _ = remove_file("test_create.db");
+        let mut kv = KV::new(String::from("test_create.db")).unwrap();
+        assert_eq!(kv.get_root(), 0);
+        assert_eq!(kv.flushed, 1);
+        let ptr = kv.new_node(&BNode::new_with_data(node_data()));
+        kv.set_root(ptr);
+        kv.flush();
+        drop(kv);
+
+        let kv = KV::new(String::from("test_create.db")).unwrap();
+        assert_eq!(kv.get_root(), ptr);
+        assert_eq!(kv.get_node(ptr).get_key(0), &[0xac]);
+    }
+
+    #[test]
+    fn test_free_list() {
+        let _ = remove_file("test_free_list.db");
+        let mut kv = KV::new(String::from("test_free_list.db")).unwrap();
+        let a = kv.new_node(&BNode::new_with_data(node_data()));
+        kv.flush();
+        // not reusable before the commit that frees it
+        kv.del_node(a);
+        let b = kv.new_node(&BNode::new_with_data(node_data()));
+        assert_ne!(a, b);
+        // never committed, reusable right away
+        kv.del_node(b);
+        assert_eq!(kv.new_node(&BNode::new_with_data(node_data())), b);
+        kv.set_root(b);
+        kv.flush();
+        drop(kv);
+
+        let mut kv = KV::new(String::from("test_free_list.db")).unwrap();
+        assert!(kv.free_pages().contains(&a));
+        assert_eq!(kv.new_node(&BNode::new_with_data(node_data())), a);
+    }
+
+    #[test]
+    fn test_grow() {
+        let _ = remove_file("test_grow.db");
+        let mut kv = KV::new(String::from("test_grow.db")).unwrap();
+        // past the first two chunks
+        let n = 2 * MAP_BASE / BTREE_PAGE_SIZE;
+        kv.grow_file(n as u64 + 1);
+        let ptr = kv.new_node(&BNode::new_with_data(node_data()));
+        kv.tail = n as u64 + 1;
+        kv.temp.insert(n as u64, kv.temp[&ptr].clone());
+        kv.flush();
+        drop(kv);
+
+        let kv = KV::new(String::from("test_grow.db")).unwrap();
+        assert_eq!(kv.file_size, (n + 1) * BTREE_PAGE_SIZE);
+        assert_eq!(kv.get_node(n as u64).get_key(0), &[0xac]);
+        drop(kv);
+        remove_file("test_grow.db").unwrap();
+    }
+
+    #[test]
+    fn test_page_size() {
+        let _ = remove_file("test_page_size.db");
+        assert!(KV::open_with_page_size(String::from("test_page_size.db"), Storage::Mmap, 3000).is_err());
+        let mut kv = KV::open_with_page_size(String::from("test_page_size.db"), Storage::Mmap, 1024).unwrap();
+        let ptr = kv.new_node(&BNode::new_with_data(node_data()));
+        kv.set_root(ptr);
+        kv.flush();
+        drop(kv);
+
+        // recorded in the meta page
+        let kv = KV::open(String::from("test_page_size.db"), Storage::Pool(4)).unwrap();
+        assert_eq!(kv.page_size(), 1024);
+        assert_eq!(kv.get_node(ptr).get_key(0), &[0xac]);
+        drop(kv);
+        assert!(KV::open_with_page_size(String::from("test_page_size.db"), Storage::Mmap, 4096).is_err());
+        let ins = Inspector::open("test_page_size.db").unwrap();
+        assert_eq!(ins.page_size(), 1024);
+        assert_eq!(ins.page(ptr).unwrap().len(), 1024);
+    }
+
+    fn open_tree(path: &str, storage: Storage, durability: Durability) -> BTree {
+        let mut tree = BTree::new(Box::new(KV::open(String::from(path), storage).unwrap()));
+        tree.set_durability(durability);
+        tree
+    }
+
+    fn assert_model(tree: &BTree, model: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        assert_eq!(tree.check(), Ok(()));
+        assert!(tree.scan(&[0x00]).eq(model.clone()));
+    }
+
+    #[test]
+    fn test_durability_sync_after() {
+        let deferred = Durability::SyncAfter(Duration::from_secs(3600));
+        for storage in [Storage::Mmap, Storage::Pool(16)] {
+            let path = "test_durability_sync_after.db";
+            let _ = remove_file(path);
+            let mut tree = open_tree(path, storage, deferred);
+            let mut model = BTreeMap::new();
+            for i in 0..500u32 {
+                tree.insert(&i.to_be_bytes(), &[0xac; 100]);
+                model.insert(i.to_be_bytes().to_vec(), vec![0xac; 100]);
+            }
+            tree.sync();
+            // rewrite every page of the synced state, then crash
+            for i in 0..500u32 {
+                tree.insert(&i.to_be_bytes(), &[0xca; 50]);
+                if i % 3 == 0 {
+                    tree.delete(&i.to_be_bytes());
+                }
+            }
+            std::mem::forget(tree);
+
+            // back to the sync, with none of its pages reused
+            let mut tree = open_tree(path, storage, deferred);
+            assert_model(&tree, &model);
+
+            // a commit of its own that syncs, then more deferred ones
+            tree.begin();
+            tree.insert(b"a", b"1");
+            tree.commit_with(Durability::Sync);
+            model.insert(b"a".to_vec(), b"1".to_vec());
+            tree.insert(b"b", b"1");
+            std::mem::forget(tree);
+            let mut tree = open_tree(path, storage, deferred);
+            assert_model(&tree, &model);
+
+            // closing syncs
+            tree.insert(b"b", b"1");
+            model.insert(b"b".to_vec(), b"1".to_vec());
+            drop(tree);
+            assert_model(&open_tree(path, storage, deferred), &model);
+
+            // compacting cuts the file only once a meta page within it is on disk
+            let mut tree = open_tree(path, storage, Durability::Sync);
+            for i in 0..450u32 {
+                tree.delete(&i.to_be_bytes());
+                model.remove(i.to_be_bytes().as_slice());
+            }
+            tree.set_durability(deferred);
+            tree.compact_in_place();
+            std::mem::forget(tree);
+            assert_model(&open_tree(path, storage, deferred), &model);
+        }
+    }
+
+    #[test]
+    fn test_durability_sync_after_idle() {
+        let deferred = Durability::SyncAfter(Duration::from_millis(300));
+        for storage in [Storage::Mmap, Storage::Pool(16)] {
+            let path = "test_durability_sync_after_idle.db";
+            let _ = remove_file(path);
+            let mut tree = open_tree(path, storage, deferred);
+            let mut model = BTreeMap::new();
+            for i in 0..100u32 {
+                tree.insert(&i.to_be_bytes(), &[0xac; 100]);
+                model.insert(i.to_be_bytes().to_vec(), vec![0xac; 100]);
+            }
+            assert_eq!(open_tree(path, storage, deferred).get(&0u32.to_be_bytes()), None);
+
+            // nothing more is committed, the thread syncs once the interval is over
+            std::thread::sleep(Duration::from_millis(600));
+            assert_model(&open_tree(path, storage, deferred), &model);
+            // and the next commit is deferred again
+            tree.insert(b"a", b"1");
+            std::thread::sleep(Duration::from_millis(600));
+            std::mem::forget(tree);
+            model.insert(b"a".to_vec(), b"1".to_vec());
+            assert_model(&open_tree(path, storage, deferred), &model);
+        }
+    }
+
+    #[test]
+    fn test_durability_none() {
+        let _ = remove_file("test_durability_none.db");
+        let mut tree = open_tree("test_durability_none.db", Storage::Pool(16), Durability::None);
+        let mut model = BTreeMap::new();
+        for i in 0..500u32 {
+            tree.insert(&i.to_be_bytes(), &[0xac; 100]);
+            model.insert(i.to_be_bytes().to_vec(), vec![0xac; 100]);
+        }
+        // a crash of the process loses nothing
+        std::mem::forget(tree);
+        assert_model(&open_tree("test_durability_none.db", Storage::Mmap, Durability::Sync), &model);
+
+        let mut tree = open_tree("test_durability_none.db", Storage::Pool(16), Durability::None);
+        for i in 0..450u32 {
+            tree.delete(&i.to_be_bytes());
+            model.remove(i.to_be_bytes().as_slice());
+        }
+        tree.compact_in_place();
+        std::mem::forget(tree);
+        assert_model(&open_tree("test_durability_none.db", Storage::Mmap, Durability::Sync), &model);
+    }
+
     #[test]
     fn test_root() {
-        init();
-        let mut kv = KV::new(String::from("test")).unwrap();
+        init("test_root.db");
+        let mut kv = KV::new(String::from("test_root.db")).unwrap();
         kv.set_root(1);
         kv.flush();
         assert_eq!(kv.get_root(), 1);
@@ -0,0 +1,142 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// pages copied per read
+const BACKUP_BATCH: u64 = 64;
+
+// a committed state of a db file; while any snapshot is alive the db
+// does not reuse the pages it frees, so the state stays intact on disk
+pub struct Snapshot {
+    path: String,
+    page_size: usize,
+    root: u64,
+    used: u64,
+    // the meta page of the state, with the free list and the kept versions
+    meta: Vec<u8>,
+    pins: Arc<AtomicUsize>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(path: &str, page_size: usize, root: u64, used: u64, meta: Vec<u8>, pins: &Arc<AtomicUsize>) -> Self {
+        pins.fetch_add(1, Ordering::SeqCst);
+        Snapshot {
+            path: String::from(path),
+            page_size,
+            root,
+            used,
+            meta,
+            pins: pins.clone(),
+        }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    // copy the pages up to the used count into a new db file, with the meta page
+    // of the snapshot; writers may go on meanwhile, from another thread
+    pub fn backup(&self, dest_path: &str) -> Result<(), String> {
+        let src = File::open(&self.path).map_err(|e| e.to_string())?;
+        let dest = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest_path).map_err(|e| e.to_string())?;
+        let page_size = self.page_size as u64;
+
+        let mut buf = vec![0; BACKUP_BATCH as usize * self.page_size];
+        let mut ptr = 1;
+        while ptr < self.used {
+            let n = BACKUP_BATCH.min(self.used - ptr);
+            let buf = &mut buf[..(n * page_size) as usize];
+            src.read_exact_at(buf, ptr * page_size).map_err(|e| e.to_string())?;
+            dest.write_all_at(buf, ptr * page_size).map_err(|e| e.to_string())?;
+            ptr += n;
+        }
+        dest.set_len(self.used * page_size).map_err(|e| e.to_string())?;
+        dest.sync_all().map_err(|e| e.to_string())?;
+
+        // the meta page last, the copy is not a db before it
+        dest.write_all_at(&self.meta, 0).map_err(|e| e.to_string())?;
+        dest.sync_all().map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::thread;
+
+    use crate::b_tree::tests::{fill, open, open_new};
+    use crate::kv::KV;
+
+    #[test]
+    fn test_backup_pinned() {
+        let _ = remove_file("test_backup_pinned.db");
+        let _ = remove_file("test_backup_pinned_copy.db");
+        let mut tree = open("test_backup_pinned.db");
+        let model = fill(&mut tree, 200, |_| vec![1; 100]);
+        let snap = tree.snapshot().unwrap();
+
+        // every page of the snapshot would be reused by now without the pin
+        fill(&mut tree, 200, |_| vec![2; 100]);
+        for i in 0..100u32 {
+            tree.delete(&i.to_be_bytes());
+        }
+        fill(&mut tree, 200, |_| vec![3; 100]);
+        snap.backup("test_backup_pinned_copy.db").unwrap();
+        assert!(snap.backup("test_backup_pinned_copy.db").is_err());
+        assert_eq!(tree.check(), Ok(()));
+
+        let copy = open("test_backup_pinned_copy.db");
+        assert_eq!(copy.check(), Ok(()));
+        assert!(copy.scan(&[0x00]).eq(model.into_iter()));
+    }
+
+    #[test]
+    fn test_backup_release() {
+        let mut tree = open_new("test_backup_release.db");
+        fill(&mut tree, 200, |_| vec![1; 100]);
+        let snap = tree.snapshot().unwrap();
+        fill(&mut tree, 200, |_| vec![2; 100]);
+        drop(snap);
+
+        // the held pages come back after a commit and stop the file from growing
+        tree.insert(b"k", b"v");
+        let kv = KV::new(String::from("test_backup_release.db")).unwrap();
+        let (used, free) = (kv.flushed, kv.free.pages().len());
+        drop(kv);
+        fill(&mut tree, 200, |_| vec![3; 100]);
+        let kv = KV::new(String::from("test_backup_release.db")).unwrap();
+        assert!(kv.flushed <= used, "{} -> {}", used, kv.flushed);
+        assert!(free > 0);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_backup_concurrent() {
+        let _ = remove_file("test_backup_concurrent.db");
+        let _ = remove_file("test_backup_concurrent_copy.db");
+        let mut tree = open("test_backup_concurrent.db");
+        let model = fill(&mut tree, 200, |_| vec![1; 100]);
+        let snap = tree.snapshot().unwrap();
+
+        let backup = thread::spawn(move || snap.backup("test_backup_concurrent_copy.db"));
+        for round in 2..6 {
+            fill(&mut tree, 200, |_| vec![round; 100]);
+        }
+        backup.join().unwrap().unwrap();
+        assert_eq!(tree.check(), Ok(()));
+
+        let copy = open("test_backup_concurrent_copy.db");
+        assert_eq!(copy.check(), Ok(()));
+        assert!(copy.scan(&[0x00]).eq(model.into_iter()));
+    }
+}
@@ -9,23 +9,23 @@ use std::slice::from_raw_parts_mut;
 use nix::libc::{munmap, off_t};
 use nix::sys::mman::{MapFlags, mmap, MsFlags, msync, ProtFlags};
 
-use crate::common::{BTREE_PAGE_SIZE, SYS_PAGE_SIZE};
+use crate::common::SYS_PAGE_SIZE;
+use crate::kv::{MAP_BASE, Pages};
 use crate::little_endian::LittleEndian;
 
 pub struct FileMap {
     ptr: NonNull<c_void>,
     size: usize,
     offset: usize,
+    page_size: usize,
     dirty: bool,
 }
 
 impl FileMap {
-    pub fn new(file: &File, size: usize, offset: usize) -> Self {
+    // the map may reach past the end of the file, only the part inside it can be touched
+    pub fn new(file: &File, size: usize, offset: usize, page_size: usize) -> Self {
         assert_eq!(offset % *SYS_PAGE_SIZE, 0);
         assert_eq!((offset + size) % *SYS_PAGE_SIZE, 0);
-        if offset + size > file.metadata().unwrap().len() as usize {
-            file.set_len((offset + size) as u64).unwrap();
-        }
         let ptr = unsafe {
             mmap(None, NonZeroUsize::new(size).unwrap(), ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
                  MapFlags::MAP_SHARED, file.as_fd(), offset as off_t).unwrap()
@@ -34,19 +34,24 @@ impl FileMap {
             ptr,
             size,
             offset,
+            page_size,
             dirty: false,
         }
     }
 
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
     pub fn n_pages(&self) -> usize {
-        self.size / BTREE_PAGE_SIZE
+        self.size / self.page_size
     }
 
     pub fn write(&mut self, pages_num: usize, data: &[u8]) {
         assert!(pages_num < self.n_pages());
-        assert!(data.len() <= BTREE_PAGE_SIZE);
+        assert!(data.len() <= self.page_size);
         let mut file_data = unsafe {
-            &mut from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, self.size)[pages_num * BTREE_PAGE_SIZE..(pages_num + 1) * BTREE_PAGE_SIZE]
+            &mut from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, self.size)[pages_num * self.page_size..(pages_num + 1) * self.page_size]
         };
         file_data.write_all(data).unwrap();
         self.dirty = true;
@@ -54,7 +59,7 @@ impl FileMap {
     pub fn read(&self, pages_num: usize) -> &[u8] {
         assert!(pages_num < self.n_pages());
         unsafe {
-            &from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, self.size)[pages_num * BTREE_PAGE_SIZE..(pages_num + 1) * BTREE_PAGE_SIZE]
+            &from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, self.size)[pages_num * self.page_size..(pages_num + 1) * self.page_size]
         }
     }
     pub fn flush(&mut self) {
@@ -65,6 +70,14 @@ impl FileMap {
             self.dirty = false;
         }
     }
+    // start writing the dirty pages back without waiting, a flush still syncs them
+    pub fn flush_async(&mut self) {
+        if self.dirty {
+            unsafe {
+                msync(self.ptr, self.size, MsFlags::MS_ASYNC).unwrap();
+            }
+        }
+    }
 }
 
 impl Drop for FileMap {
@@ -75,6 +88,78 @@ impl Drop for FileMap {
     }
 }
 
+// the file mapped in chunks of doubling size: | base | base | 2 base | 4 base | ...
+pub struct FileMaps {
+    file: File,
+    page_size: usize,
+    maps: Vec<FileMap>,
+    size: usize,
+}
+
+impl FileMaps {
+    pub fn new(file: File, page_size: usize) -> Self {
+        FileMaps {
+            file,
+            page_size,
+            maps: Vec::new(),
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+
+    // chunk and page within it, chunk i > 0 starts at base << (i - 1)
+    pub fn page_pos(ptr: u64, page_size: usize) -> (usize, usize) {
+        let offset = ptr as usize * page_size;
+        let q = offset / MAP_BASE;
+        if q == 0 {
+            return (0, ptr as usize);
+        }
+        let row = (usize::BITS - q.leading_zeros()) as usize;
+        let start = MAP_BASE << (row - 1);
+        (row, (offset - start) / page_size)
+    }
+}
+
+impl Pages for FileMaps {
+    fn read(&self, ptr: u64) -> Vec<u8> {
+        let (row, col) = Self::page_pos(ptr, self.page_size);
+        self.maps[row].read(col).to_vec()
+    }
+
+    fn write(&mut self, ptr: u64, data: &[u8]) {
+        let (row, col) = Self::page_pos(ptr, self.page_size);
+        self.maps[row].write(col, data);
+    }
+
+    // map chunks until the first `size` bytes of the file are covered
+    fn grow(&mut self, size: usize) {
+        while self.size < size {
+            let chunk = if self.maps.is_empty() { MAP_BASE } else { self.size };
+            self.maps.push(FileMap::new(&self.file, chunk, self.size, self.page_size));
+            self.size += chunk;
+        }
+    }
+
+    fn flush(&mut self) {
+        self.maps.iter_mut().for_each(|m| m.flush());
+    }
+
+    fn write_back(&mut self) {
+        self.maps.iter_mut().for_each(|m| m.flush_async());
+    }
+}
+
 impl LittleEndian for FileMap {
     fn read_u16(&self, start: usize) -> u16 {
         let data = unsafe {
@@ -91,6 +176,7 @@ impl LittleEndian for FileMap {
         };
         data[0] = low;
         data[1] = hi;
+        self.dirty = true;
     }
 }
 
@@ -98,21 +184,25 @@ impl LittleEndian for FileMap {
 mod tests {
     use std::fs::OpenOptions;
 
+    use crate::common::BTREE_PAGE_SIZE;
+
     use super::*;
 
-    fn file_create() -> File {
+    fn file_create(path: &str) -> File {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("test").unwrap();
+            .truncate(false)
+            .open(path).unwrap();
         file
     }
 
     #[test]
     fn test_file_map() {
-        let f = file_create();
-        let mut file_map = FileMap::new(&f, *SYS_PAGE_SIZE, 0);
+        let f = file_create("test_file_map.db");
+        f.set_len(4 * BTREE_PAGE_SIZE as u64).unwrap();
+        let mut file_map = FileMap::new(&f, 4 * BTREE_PAGE_SIZE, 0, BTREE_PAGE_SIZE);
         file_map.write(0, &[0xac, 0xac]);
         file_map.write(1, &[0xab, 0xab]);
         file_map.write(2, &[0xee, 0xee]);
@@ -124,4 +214,29 @@ mod tests {
         assert_eq!(file_map.n_pages(), 4);
         file_map.flush();
     }
+
+    #[test]
+    fn test_page_pos() {
+        let base = (MAP_BASE / BTREE_PAGE_SIZE) as u64;
+        assert_eq!(FileMaps::page_pos(0, BTREE_PAGE_SIZE), (0, 0));
+        assert_eq!(FileMaps::page_pos(base - 1, BTREE_PAGE_SIZE), (0, base as usize - 1));
+        assert_eq!(FileMaps::page_pos(base, BTREE_PAGE_SIZE), (1, 0));
+        assert_eq!(FileMaps::page_pos(2 * base - 1, BTREE_PAGE_SIZE), (1, base as usize - 1));
+        assert_eq!(FileMaps::page_pos(2 * base, BTREE_PAGE_SIZE), (2, 0));
+        assert_eq!(FileMaps::page_pos(4 * base + 3, BTREE_PAGE_SIZE), (3, 3));
+    }
+
+    #[test]
+    fn test_file_maps_grow() {
+        let f = file_create("test_file_maps_grow.db");
+        let mut maps = FileMaps::new(f, BTREE_PAGE_SIZE);
+        maps.grow(BTREE_PAGE_SIZE);
+        assert_eq!(maps.len(), 1);
+        // past the first two chunks
+        maps.grow(2 * MAP_BASE + BTREE_PAGE_SIZE);
+        assert_eq!(maps.len(), 3);
+        assert_eq!(maps.size(), 4 * MAP_BASE);
+        maps.grow(4 * MAP_BASE);
+        assert_eq!(maps.len(), 3);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use crate::b_node::BNode;
+use crate::little_endian::LittleEndian;
+
+// free list page: | type | size | next | ptrs... |
+pub const FREE_LIST_TYPE: u16 = 3;
+// retired page: | type | size | next | (ptr, version freed, oldest version using it)... |
+pub const RETIRED_LIST_TYPE: u16 = 4;
+const FREE_LIST_HEADER: usize = 12;
+
+#[derive(Default)]
+pub struct FreeList {
+    page_size: usize,
+    head: u64,
+    // pages holding the list itself
+    chain: Vec<u64>,
+    // free in the committed state, safe to overwrite now
+    free: Vec<u64>,
+    // freed by the current txn, still referenced by the committed root
+    freed: Vec<u64>,
+    // freed while a snapshot pins an older root, on disk they are free
+    held: Vec<u64>,
+    // (version freed, oldest version using it, ptr), tree pages a retained version may still use
+    retired: Vec<(u64, u64, u64)>,
+}
+
+impl FreeList {
+    pub fn new(page_size: usize) -> Self {
+        FreeList { page_size, ..Default::default() }
+    }
+
+    pub fn load(head: u64, page_size: usize, page: impl Fn(u64) -> Vec<u8>) -> Result<Self, String> {
+        let mut list = FreeList { page_size, head, ..Default::default() };
+        let mut next = head;
+        while next != 0 {
+            if list.chain.contains(&next) {
+                return Err(format!("free list loops at page {}", next));
+            }
+            let node = BNode::new_with_data(page(next));
+            let size = node.read_u16(2) as usize;
+            let cap = match node.read_u16(0) {
+                FREE_LIST_TYPE => list.cap(),
+                RETIRED_LIST_TYPE => list.retired_cap(),
+                _ => return Err(format!("page {} is not a free list page", next)),
+            };
+            if size > cap {
+                return Err(format!("free list page {} holds {} ptrs", next, size));
+            }
+            list.chain.push(next);
+            for i in 0..size {
+                if node.read_u16(0) == FREE_LIST_TYPE {
+                    list.free.push(node.read_u64(FREE_LIST_HEADER + 8 * i));
+                } else {
+                    let at = FREE_LIST_HEADER + 24 * i;
+                    list.retired.push((node.read_u64(at + 8), node.read_u64(at + 16), node.read_u64(at)));
+                }
+            }
+            next = node.read_u64(4);
+        }
+        list.sort();
+        Ok(list)
+    }
+
+    // ptrs per page
+    pub fn cap(&self) -> usize {
+        (self.page_size - FREE_LIST_HEADER) / 8
+    }
+    fn retired_cap(&self) -> usize {
+        (self.page_size - FREE_LIST_HEADER) / 24
+    }
+
+    pub fn head(&self) -> u64 {
+        self.head
+    }
+
+    // every page owned by the list, storage included
+    pub fn pages(&self) -> Vec<u64> {
+        let mut pages = self.chain.clone();
+        pages.extend_from_slice(&self.free);
+        pages.extend_from_slice(&self.freed);
+        pages.extend_from_slice(&self.held);
+        pages.extend(self.retired.iter().map(|(_, _, ptr)| *ptr));
+        pages
+    }
+
+    // lowest page first, keeps the file dense
+    pub fn pop(&mut self) -> Option<u64> {
+        self.free.pop()
+    }
+
+    fn sort(&mut self) {
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    // free pages at the end of `[1, used)`, where the file can be cut
+    pub fn tail_from(&self, used: u64) -> u64 {
+        let mut end = used;
+        // sorted from the highest
+        for ptr in &self.free {
+            if *ptr + 1 != end {
+                break;
+            }
+            end -= 1;
+        }
+        end
+    }
+
+    // forget the free pages from `end` on, the file is cut there
+    pub fn truncate(&mut self, end: u64) {
+        assert!(self.chain.iter().chain(&self.freed).all(|ptr| *ptr < end));
+        self.free.retain(|ptr| *ptr < end);
+    }
+
+    // page of the committed state, reusable after the next commit
+    pub fn push(&mut self, ptr: u64) {
+        self.freed.push(ptr);
+    }
+
+    // page that never reached the committed state, reusable right away
+    pub fn reuse(&mut self, ptr: u64) {
+        self.free.push(ptr);
+    }
+
+    // tree pages freed by the current txn
+    pub fn freed(&self) -> &[u64] {
+        &self.freed
+    }
+
+    // lay the list out for the commit of `version`, `alloc` appends a page to the file;
+    // the freed pages in `first_use` are used by a retained version, the oldest one given,
+    // and stay out of use while `kept(first use, version freed)` holds.
+    // with `hold` the pages this commit frees stay out of use, until a commit with `release`
+    // once no committed state on disk or pinned points to them.
+    // returns the pages to write, the head is only valid once they are written
+    pub fn commit(&mut self, release: bool, hold: bool, version: u64, first_use: &HashMap<u64, u64>,
+                  kept: impl Fn(u64, u64) -> bool, mut alloc: impl FnMut() -> u64) -> Vec<(u64, Vec<u8>)> {
+        let (retire, mut pending): (Vec<u64>, Vec<u64>) = self.freed.drain(..).partition(|ptr| first_use.contains_key(ptr));
+        self.retired.extend(retire.into_iter().map(|ptr| (version, first_use[&ptr], ptr)));
+        pending.append(&mut self.chain);
+        // no retained version uses them anymore
+        let mut released = Vec::new();
+        self.retired.retain(|(freed, first, ptr)| {
+            if !kept(*first, *freed) {
+                released.push(*ptr);
+                return false;
+            }
+            true
+        });
+        if release {
+            // no committed state points to them
+            self.free.append(&mut self.held);
+            self.free.append(&mut released);
+            self.sort();
+        } else {
+            self.held.append(&mut released);
+        }
+        if hold {
+            self.held.append(&mut pending);
+        }
+
+        // storage may only come from pages no committed state points to
+        let (cap, retired_cap) = (self.cap(), self.retired_cap());
+        let mut chain = Vec::new();
+        loop {
+            let n = self.free.len() + self.held.len() + pending.len();
+            if chain.len() >= n.div_ceil(cap) + self.retired.len().div_ceil(retired_cap) {
+                break;
+            }
+            chain.push(self.free.pop().unwrap_or_else(&mut alloc));
+        }
+        self.free.append(&mut pending);
+        self.sort();
+
+        // free pages first, then the retired ones; the last pages may end up empty
+        let entries: Vec<u64> = self.free.iter().chain(&self.held).copied().collect();
+        let mut nodes = Vec::new();
+        for chunk in entries.chunks(cap) {
+            let mut node = self.list_node(FREE_LIST_TYPE, chunk.len());
+            for (j, ptr) in chunk.iter().enumerate() {
+                node.write_u64(FREE_LIST_HEADER + 8 * j, *ptr);
+            }
+            nodes.push(node);
+        }
+        for chunk in self.retired.chunks(retired_cap) {
+            let mut node = self.list_node(RETIRED_LIST_TYPE, chunk.len());
+            for (j, (freed, first, ptr)) in chunk.iter().enumerate() {
+                node.write_u64(FREE_LIST_HEADER + 24 * j, *ptr);
+                node.write_u64(FREE_LIST_HEADER + 24 * j + 8, *freed);
+                node.write_u64(FREE_LIST_HEADER + 24 * j + 16, *first);
+            }
+            nodes.push(node);
+        }
+        while nodes.len() < chain.len() {
+            nodes.push(self.list_node(FREE_LIST_TYPE, 0));
+        }
+        assert_eq!(nodes.len(), chain.len());
+
+        let mut pages = Vec::new();
+        for (i, mut node) in nodes.into_iter().enumerate() {
+            node.write_u64(4, chain.get(i + 1).copied().unwrap_or(0));
+            pages.push((chain[i], node.get_bytes(0, self.page_size as u16).to_vec()));
+        }
+        self.head = chain.first().copied().unwrap_or(0);
+        self.chain = chain;
+        pages
+    }
+
+    fn list_node(&self, n_type: u16, size: usize) -> BNode {
+        let mut node = BNode::new_with_cap(self.page_size);
+        node.write_u16(0, n_type);
+        node.write_u16(2, size as u16);
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::common::BTREE_PAGE_SIZE;
+
+    use super::*;
+
+    fn commit(list: &mut FreeList, disk: &mut HashMap<u64, Vec<u8>>, used: &mut u64) {
+        commit_with(list, disk, used, false);
+    }
+
+    fn commit_with(list: &mut FreeList, disk: &mut HashMap<u64, Vec<u8>>, used: &mut u64, hold: bool) {
+        let pages = list.commit(!hold, hold, 0, &HashMap::new(), |_, _| false, || {
+            *used += 1;
+            *used - 1
+        });
+        for (ptr, data) in pages {
+            disk.insert(ptr, data);
+        }
+    }
+
+    #[test]
+    fn test_commit_and_load() {
+        let mut disk = HashMap::new();
+        let mut used = 10;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        list.push(3);
+        list.push(5);
+        commit(&mut list, &mut disk, &mut used);
+        // no free page yet, the storage is appended
+        assert_eq!(used, 11);
+        assert_eq!(list.head(), 10);
+
+        let mut loaded = FreeList::load(list.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap();
+        let mut pages = loaded.pages();
+        pages.sort();
+        assert_eq!(pages, vec![3, 5, 10]);
+
+        // the storage moves into a free page, the old one is freed
+        commit(&mut loaded, &mut disk, &mut used);
+        assert_eq!(used, 11);
+        assert_ne!(loaded.head(), 10);
+        let mut pages = FreeList::load(loaded.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap().pages();
+        pages.sort();
+        assert_eq!(pages, vec![3, 5, 10]);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut disk = HashMap::new();
+        let mut used = 1;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        list.push(7);
+        assert_eq!(list.pop(), None);
+        commit(&mut list, &mut disk, &mut used);
+        assert_eq!(list.pop(), Some(7));
+        assert_eq!(list.pop(), None);
+        list.reuse(7);
+        assert_eq!(list.pop(), Some(7));
+    }
+
+    #[test]
+    fn test_pop_lowest() {
+        let mut disk = HashMap::new();
+        let mut used = 10;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        for ptr in [9, 3, 7, 5] {
+            list.push(ptr);
+        }
+        commit(&mut list, &mut disk, &mut used);
+        // the storage sits at the end
+        assert_eq!(list.head(), 10);
+        assert_eq!(list.tail_from(used), used);
+
+        // and moves to the lowest free page
+        commit(&mut list, &mut disk, &mut used);
+        assert_eq!(list.head(), 3);
+        assert_eq!(list.tail_from(used), 9);
+        list.truncate(9);
+        assert_eq!(list.pop(), Some(5));
+        assert_eq!(list.pop(), Some(7));
+        assert_eq!(list.pop(), None);
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut disk = HashMap::new();
+        let mut used = 10;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        list.push(3);
+        commit_with(&mut list, &mut disk, &mut used, true);
+        list.push(4);
+        commit_with(&mut list, &mut disk, &mut used, true);
+        assert_eq!(list.pop(), None);
+
+        // on disk they are plain free pages
+        let mut loaded = FreeList::load(list.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap();
+        let mut pages = loaded.pages();
+        pages.sort();
+        assert_eq!(pages, vec![3, 4, 10, 11]);
+        assert_eq!(loaded.pop(), Some(3));
+
+        // released by the first commit without hold
+        commit_with(&mut list, &mut disk, &mut used, false);
+        assert_eq!(list.head(), 3);
+        assert_eq!(list.pop(), Some(4));
+    }
+
+    #[test]
+    fn test_retired() {
+        let mut disk = HashMap::new();
+        let mut used = 10;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        list.push(3);
+        list.push(5);
+        // version 1 uses 3, freed by 2
+        let pages = list.commit(true, false, 2, &HashMap::from([(3, 1)]), |first, freed| first == 1 && freed == 2, || {
+            used += 1;
+            used - 1
+        });
+        for (ptr, data) in pages {
+            disk.insert(ptr, data);
+        }
+        // a page of its own for the retired ones
+        assert_eq!(used, 12);
+        assert_eq!(list.pop(), Some(5));
+        assert_eq!(list.pop(), None);
+
+        let mut loaded = FreeList::load(list.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap();
+        assert_eq!(loaded.retired, vec![(2, 1, 3)]);
+        // kept while version 1 is
+        loaded.commit(true, false, 3, &HashMap::new(), |first, _| first == 1, || {
+            used += 1;
+            used - 1
+        });
+        assert_eq!(loaded.retired, vec![(2, 1, 3)]);
+        // released once no version keeps it
+        commit(&mut loaded, &mut disk, &mut used);
+        assert!(loaded.retired.is_empty());
+        assert_eq!(loaded.head(), 3);
+        assert_eq!(loaded.pop(), Some(5));
+    }
+
+    #[test]
+    fn test_empty_tail_page() {
+        let mut disk = HashMap::new();
+        let mut used = 10000;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        for ptr in 1..=(list.cap() as u64 + 2) {
+            list.reuse(ptr);
+        }
+        commit(&mut list, &mut disk, &mut used);
+        assert_eq!(used, 10000);
+        let loaded = FreeList::load(list.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap();
+        assert_eq!(loaded.pages().len(), list.cap() + 2);
+    }
+
+    #[test]
+    fn test_many_pages() {
+        let mut disk = HashMap::new();
+        let mut used = 10000;
+        let mut list = FreeList::new(BTREE_PAGE_SIZE);
+        for ptr in 1..=(2 * list.cap() as u64 + 1) {
+            list.push(ptr);
+        }
+        commit(&mut list, &mut disk, &mut used);
+        assert_eq!(used, 10003);
+        let loaded = FreeList::load(list.head(), BTREE_PAGE_SIZE, |ptr| disk[&ptr].clone()).unwrap();
+        assert_eq!(loaded.pages().len(), 2 * list.cap() + 4);
+        assert!(FreeList::load(1, BTREE_PAGE_SIZE, |_| vec![0; BTREE_PAGE_SIZE]).is_err());
+    }
+}
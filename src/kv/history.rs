@@ -0,0 +1,129 @@
+use crate::kv::META_HISTORY;
+
+// longest name of a tag
+pub const MAX_TAG_SIZE: usize = 64;
+// | version | root | name len |
+const ENTRY_SIZE: usize = 17;
+
+// a committed root the db keeps readable
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub version: u64,
+    pub root: u64,
+    // set for tagged versions, they stay until untagged
+    pub name: Option<String>,
+}
+
+// the versions kept in the meta page after the fixed fields:
+// | retain u16 | n u16 | (version u64, root u64, name len u8, name)... |
+#[derive(Default)]
+pub struct History {
+    // the last this many commits are kept, 0 keeps none
+    retain: usize,
+    // oldest first
+    versions: Vec<Version>,
+    tags: Vec<Version>,
+}
+
+impl History {
+    pub fn decode(meta: &[u8]) -> Result<History, String> {
+        let mut history = History::default();
+        let data = &meta[META_HISTORY..];
+        history.retain = u16::from_le_bytes([data[0], data[1]]) as usize;
+        let n = u16::from_le_bytes([data[2], data[3]]) as usize;
+        let mut pos = 4;
+        for _ in 0..n {
+            if pos + ENTRY_SIZE > data.len() {
+                return Err(String::from("history beyond the meta page"));
+            }
+            let version = u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+            let root = u64::from_le_bytes(data[pos + 8..pos + 16].try_into().unwrap());
+            let len = data[pos + 16] as usize;
+            pos += ENTRY_SIZE;
+            if pos + len > data.len() {
+                return Err(String::from("history beyond the meta page"));
+            }
+            let name = match len {
+                0 => None,
+                _ => Some(String::from_utf8(data[pos..pos + len].to_vec()).map_err(|e| e.to_string())?),
+            };
+            pos += len;
+            match name {
+                None => history.versions.push(Version { version, root, name }),
+                Some(_) => history.tags.push(Version { version, root, name }),
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = (self.retain as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(&((self.versions.len() + self.tags.len()) as u16).to_le_bytes());
+        for v in self.versions.iter().chain(&self.tags) {
+            let name = v.name.as_deref().unwrap_or("").as_bytes();
+            data.extend_from_slice(&v.version.to_le_bytes());
+            data.extend_from_slice(&v.root.to_le_bytes());
+            data.push(name.len() as u8);
+            data.extend_from_slice(name);
+        }
+        data
+    }
+
+    // bytes once the auto versions are all there
+    fn full_size(&self, retain: usize, tags: &[Version]) -> usize {
+        let names: usize = tags.iter().map(|v| v.name.as_ref().unwrap().len()).sum();
+        META_HISTORY + 4 + (retain + tags.len()) * ENTRY_SIZE + names
+    }
+
+    pub fn retain(&self) -> usize {
+        self.retain
+    }
+
+    pub fn set_retain(&mut self, retain: usize, page_size: usize) -> Result<(), String> {
+        if self.full_size(retain, &self.tags) > page_size {
+            return Err(format!("{} versions do not fit in the meta page", retain));
+        }
+        self.retain = retain;
+        let n = self.versions.len().saturating_sub(retain);
+        self.versions.drain(..n);
+        Ok(())
+    }
+
+    // a commit, the oldest auto version goes past the retain count
+    pub fn record(&mut self, version: u64, root: u64) {
+        if self.retain == 0 {
+            return;
+        }
+        if self.versions.len() == self.retain {
+            self.versions.remove(0);
+        }
+        self.versions.push(Version { version, root, name: None });
+    }
+
+    pub fn tag(&mut self, name: &str, version: u64, root: u64, page_size: usize) -> Result<(), String> {
+        if name.is_empty() || name.len() > MAX_TAG_SIZE {
+            return Err(format!("tag of {} bytes", name.len()));
+        }
+        let mut tags: Vec<Version> = self.tags.iter().filter(|v| v.name.as_deref() != Some(name)).cloned().collect();
+        tags.push(Version { version, root, name: Some(String::from(name)) });
+        if self.full_size(self.retain, &tags) > page_size {
+            return Err(String::from("no room for another tag in the meta page"));
+        }
+        self.tags = tags;
+        Ok(())
+    }
+
+    pub fn untag(&mut self, name: &str) -> Result<(), String> {
+        let n = self.tags.len();
+        self.tags.retain(|v| v.name.as_deref() != Some(name));
+        if self.tags.len() == n {
+            return Err(format!("no tag {}", name));
+        }
+        Ok(())
+    }
+
+    // oldest first, the tags last
+    pub fn versions(&self) -> Vec<Version> {
+        self.versions.iter().chain(&self.tags).cloned().collect()
+    }
+}
@@ -0,0 +1,193 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use crate::kv::Pages;
+
+struct Frame {
+    data: Vec<u8>,
+    dirty: bool,
+    // last use, the smallest goes first
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Frames {
+    frames: HashMap<u64, Frame>,
+    // tick -> ptr, in lru order
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// a fixed number of pages cached in memory, read and written with pread/pwrite;
+// dirty pages reach the file when they are evicted or flushed
+pub struct Pool {
+    file: File,
+    cap: usize,
+    page_size: usize,
+    // reads update the lru order
+    frames: RefCell<Frames>,
+    // pages written to the file since the last sync, by flushes or evictions
+    unsynced: Cell<bool>,
+}
+
+impl Pool {
+    pub fn new(file: File, cap: usize, page_size: usize) -> Self {
+        assert!(cap > 0);
+        Pool {
+            file,
+            cap,
+            page_size,
+            frames: RefCell::new(Frames::default()),
+            unsynced: Cell::new(false),
+        }
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    // (hits, misses) of reads so far
+    pub fn stats(&self) -> (u64, u64) {
+        let frames = self.frames.borrow();
+        (frames.hits, frames.misses)
+    }
+
+    fn insert(&self, frames: &mut Frames, ptr: u64, mut frame: Frame) {
+        if frames.frames.len() >= self.cap {
+            let (_, victim) = frames.lru.pop_first().unwrap();
+            let old = frames.frames.remove(&victim).unwrap();
+            if old.dirty {
+                self.file.write_all_at(&old.data, victim * self.page_size as u64).unwrap();
+                self.unsynced.set(true);
+            }
+        }
+        frames.tick += 1;
+        frame.tick = frames.tick;
+        frames.lru.insert(frame.tick, ptr);
+        frames.frames.insert(ptr, frame);
+    }
+}
+
+impl Pages for Pool {
+    fn read(&self, ptr: u64) -> Vec<u8> {
+        let mut frames = self.frames.borrow_mut();
+        if frames.frames.contains_key(&ptr) {
+            frames.hits += 1;
+            frames.touch(ptr);
+            return frames.frames[&ptr].data.clone();
+        }
+        frames.misses += 1;
+        let mut data = vec![0; self.page_size];
+        self.file.read_exact_at(&mut data, ptr * self.page_size as u64).unwrap();
+        self.insert(&mut frames, ptr, Frame { data: data.clone(), dirty: false, tick: 0 });
+        data
+    }
+
+    // the rest of the page is zeroed
+    fn write(&mut self, ptr: u64, data: &[u8]) {
+        assert!(data.len() <= self.page_size);
+        let mut page = vec![0; self.page_size];
+        page[..data.len()].copy_from_slice(data);
+        let frames = self.frames.get_mut();
+        if let Some(frame) = frames.frames.get_mut(&ptr) {
+            frame.data = page;
+            frame.dirty = true;
+            frames.touch(ptr);
+            return;
+        }
+        let mut frames = self.frames.borrow_mut();
+        self.insert(&mut frames, ptr, Frame { data: page, dirty: true, tick: 0 });
+    }
+
+    // write the dirty pages in file order, then sync
+    fn flush(&mut self) {
+        self.write_back();
+        if self.unsynced.replace(false) {
+            self.file.sync_data().unwrap();
+        }
+    }
+
+    // write the dirty pages in file order, the os syncs them when it likes
+    fn write_back(&mut self) {
+        let frames = self.frames.get_mut();
+        let mut dirty: Vec<_> = frames.frames.iter_mut().filter(|(_, f)| f.dirty).collect();
+        dirty.sort_by_key(|(ptr, _)| **ptr);
+        for (ptr, frame) in dirty {
+            self.file.write_all_at(&frame.data, ptr * self.page_size as u64).unwrap();
+            frame.dirty = false;
+            self.unsynced.set(true);
+        }
+    }
+}
+
+impl Frames {
+    fn touch(&mut self, ptr: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        let frame = self.frames.get_mut(&ptr).unwrap();
+        self.lru.remove(&frame.tick);
+        frame.tick = tick;
+        self.lru.insert(tick, ptr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::common::BTREE_PAGE_SIZE;
+
+    use super::*;
+
+    fn pool(path: &str, n_pages: u64, cap: usize) -> Pool {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap();
+        file.set_len(n_pages * BTREE_PAGE_SIZE as u64).unwrap();
+        Pool::new(file, cap, BTREE_PAGE_SIZE)
+    }
+
+    fn on_disk(pool: &Pool, ptr: u64) -> u8 {
+        let mut b = [0];
+        pool.file.read_exact_at(&mut b, ptr * BTREE_PAGE_SIZE as u64).unwrap();
+        b[0]
+    }
+
+    #[test]
+    fn test_pool_lru() {
+        let mut pool = pool("test_pool_lru.db", 8, 2);
+        pool.write(1, &[0x01]);
+        pool.write(2, &[0x02]);
+        // 1 is used last, 2 goes first
+        assert_eq!(pool.read(1)[0], 0x01);
+        pool.write(3, &[0x03]);
+        assert_eq!(on_disk(&pool, 2), 0x02);
+        assert_eq!(on_disk(&pool, 1), 0x00);
+
+        assert_eq!(pool.stats(), (1, 0));
+        assert_eq!(pool.read(2)[0], 0x02);
+        assert_eq!(pool.stats(), (1, 1));
+        // 1 was evicted by the read of 2
+        assert_eq!(on_disk(&pool, 1), 0x01);
+    }
+
+    #[test]
+    fn test_pool_flush() {
+        let mut pool = pool("test_pool_flush.db", 8, 4);
+        pool.write(5, &[0xac; 10]);
+        pool.write(5, &[0xca]);
+        assert_eq!(on_disk(&pool, 5), 0x00);
+        pool.flush();
+        assert_eq!(on_disk(&pool, 5), 0xca);
+        let page = pool.read(5);
+        assert_eq!(page.len(), BTREE_PAGE_SIZE);
+        assert_eq!(page[1], 0x00);
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// what the kv and its syncer thread share
+struct State {
+    // the meta page of the last deferred commit, its version and the interval it was made with
+    pending: Option<(u64, Vec<u8>, Duration)>,
+    // the version whose meta page was synced last, and when
+    synced: (u64, Instant),
+    stop: bool,
+}
+
+// syncs the meta page of a deferred commit once the interval since the last sync is over,
+// so commits do not stay unsynced for longer on an idle db. the pages of the commit are
+// already with the os, the thread syncs the file and writes the meta page past the maps
+pub struct Syncer {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    // started by the first deferred commit
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub fn new(version: u64) -> Syncer {
+        let state = State { pending: None, synced: (version, Instant::now()), stop: false };
+        Syncer { shared: Arc::new((Mutex::new(state), Condvar::new())), thread: None }
+    }
+
+    // the version and time of the last sync, by the kv or the thread
+    pub fn last_sync(&self) -> (u64, Instant) {
+        self.shared.0.lock().unwrap().synced
+    }
+
+    // the meta page goes to disk `interval` after the last sync, unless the kv syncs first
+    pub fn defer(&mut self, file: &File, version: u64, meta: Vec<u8>, interval: Duration) {
+        if self.thread.is_none() {
+            let (file, shared) = (file.try_clone().unwrap(), self.shared.clone());
+            self.thread = Some(thread::spawn(move || run(file, &shared)));
+        }
+        let (lock, cond) = &*self.shared;
+        lock.lock().unwrap().pending = Some((version, meta, interval));
+        cond.notify_one();
+    }
+
+    // the kv is about to write the meta page itself, the pending one is older;
+    // returns once a sync in progress is done
+    pub fn cancel(&self) {
+        self.shared.0.lock().unwrap().pending = None;
+    }
+
+    // the kv synced the meta page of the version itself
+    pub fn synced(&self, version: u64) {
+        let mut state = self.shared.0.lock().unwrap();
+        state.pending = None;
+        state.synced = (version, Instant::now());
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        let (lock, cond) = &*self.shared;
+        lock.lock().unwrap().stop = true;
+        cond.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// the lock is held while syncing, so a meta page of the kv never goes before an older one
+fn run(file: File, shared: &(Mutex<State>, Condvar)) {
+    let (lock, cond) = shared;
+    let mut state = lock.lock().unwrap();
+    while !state.stop {
+        let Some((_, _, interval)) = state.pending else {
+            state = cond.wait(state).unwrap();
+            continue;
+        };
+        let due = state.synced.1 + interval;
+        let now = Instant::now();
+        if now < due {
+            state = cond.wait_timeout(state, due - now).unwrap().0;
+            continue;
+        }
+        let (version, meta, _) = state.pending.take().unwrap();
+        // the pages first, the meta page only once they are on disk
+        file.sync_data().unwrap();
+        file.write_all_at(&meta, 0).unwrap();
+        file.sync_data().unwrap();
+        state.synced = (version, Instant::now());
+    }
+}
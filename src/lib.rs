@@ -1,5 +1,11 @@
-mod b_node;
-mod b_tree;
-mod common;
-mod little_endian;
-mod kv;
\ No newline at end of file
+pub mod b_node;
+pub mod b_tree;
+pub mod common;
+pub mod little_endian;
+pub mod kv;
+pub mod inspect;
+pub mod fault;
+pub mod wal;
+pub mod ttl;
+pub mod cdc;
+pub mod group;
@@ -21,6 +21,6 @@ pub trait LittleEndian {
 
     fn write_u64(&mut self, start: usize, data: u64) {
         self.write_u32(start, (data & 0xffffffff) as u32);
-        self.write_u32(start + 2, (data >> 32) as u32);
+        self.write_u32(start + 4, (data >> 32) as u32);
     }
 }
\ No newline at end of file
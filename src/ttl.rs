@@ -0,0 +1,253 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::b_tree::BTree;
+use crate::kv::KV;
+
+// the tree holds two key ranges: the kvs under DATA as | DATA | key | with vals
+// | deadline u64 LE | val |, and the expiry index under INDEX as
+// | INDEX | deadline u64 BE | key | with empty vals, so it is in deadline order
+const INDEX: u8 = 0;
+const DATA: u8 = 1;
+// deadline of keys that do not expire
+const NEVER: u64 = 0;
+
+// keys that can expire; expired keys are hidden from reads right away
+// and deleted by sweep()
+pub struct TtlDB {
+    tree: BTree,
+    // millis since the epoch
+    clock: Box<dyn Fn() -> u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn data_key(key: &[u8]) -> Vec<u8> {
+    let mut data = vec![DATA];
+    data.extend_from_slice(key);
+    data
+}
+
+fn index_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut data = vec![INDEX];
+    data.extend_from_slice(&deadline.to_be_bytes());
+    data.extend_from_slice(key);
+    data
+}
+
+fn deadline(val: &[u8]) -> u64 {
+    u64::from_le_bytes(val[..8].try_into().unwrap())
+}
+
+fn deadline_of(index: &[u8]) -> u64 {
+    u64::from_be_bytes(index[1..9].try_into().unwrap())
+}
+
+impl TtlDB {
+    pub fn open(path: &str) -> Result<TtlDB, String> {
+        Ok(TtlDB {
+            tree: BTree::new(Box::new(KV::new(String::from(path))?)),
+            clock: Box::new(now),
+        })
+    }
+
+    // where expiry gets the time from
+    pub fn set_clock(&mut self, clock: Box<dyn Fn() -> u64>) {
+        self.clock = clock;
+    }
+
+    // room for the prefix and the deadline
+    pub fn max_key_size(&self) -> usize {
+        self.tree.max_key_size() - 9
+    }
+    pub fn max_val_size(&self) -> usize {
+        self.tree.max_val_size() - 8
+    }
+
+    fn live(&self, val: &[u8]) -> bool {
+        let deadline = deadline(val);
+        deadline == NEVER || deadline > (self.clock)()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        assert_ne!(key.len(), 0);
+        let val = self.tree.get(&data_key(key))?;
+        self.live(&val).then(|| val[8..].to_vec())
+    }
+
+    // time left before the key expires, None for keys without a ttl or already expired
+    pub fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        assert_ne!(key.len(), 0);
+        let val = self.tree.get(&data_key(key)).filter(|v| self.live(v))?;
+        match deadline(&val) {
+            NEVER => None,
+            deadline => Some(Duration::from_millis(deadline.saturating_sub((self.clock)()))),
+        }
+    }
+
+    // the live kvs with key >= start, in key order
+    pub fn scan(&self, start: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.tree
+            .scan(&data_key(start))
+            .take_while(|(k, _)| k.first() == Some(&DATA))
+            .filter(|(_, v)| self.live(v))
+            .map(|(k, v)| (k[1..].to_vec(), v[8..].to_vec()))
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
+        self.put(key, val, NEVER);
+    }
+
+    // a ttl past the end of the clock is cut to it
+    pub fn insert_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = (self.clock)().saturating_add(ttl);
+        self.put(key, val, deadline.max(1));
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8], deadline: u64) {
+        assert_ne!(key.len(), 0);
+        assert!(key.len() <= self.max_key_size());
+        assert!(val.len() <= self.max_val_size());
+        let mut data = deadline.to_le_bytes().to_vec();
+        data.extend_from_slice(val);
+
+        // the kv and its index entry change in one commit
+        self.tree.begin();
+        self.unindex(key);
+        self.tree.insert(&data_key(key), &data);
+        if deadline != NEVER {
+            self.tree.insert(&index_key(deadline, key), &[]);
+        }
+        self.tree.commit();
+    }
+
+    fn unindex(&mut self, key: &[u8]) {
+        if let Some(old) = self.tree.get(&data_key(key)) {
+            if deadline(&old) != NEVER {
+                self.tree.delete(&index_key(deadline(&old), key));
+            }
+        }
+    }
+
+    // an expired key is already gone
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        assert_ne!(key.len(), 0);
+        let live = self.get(key).is_some();
+        self.tree.begin();
+        self.unindex(key);
+        self.tree.delete(&data_key(key));
+        self.tree.commit();
+        live
+    }
+
+    // delete up to `batch` expired keys in one commit, the ones expired longest first;
+    // returns how many went, call again while it is `batch`
+    pub fn sweep(&mut self, batch: usize) -> usize {
+        let now = (self.clock)();
+        let expired: Vec<Vec<u8>> = self.tree
+            .scan(&[INDEX])
+            .take_while(|(k, _)| k[0] == INDEX && deadline_of(k) <= now)
+            .take(batch)
+            .map(|(k, _)| k)
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+        self.tree.begin();
+        for index in &expired {
+            self.tree.delete(index);
+            self.tree.delete(&data_key(&index[9..]));
+        }
+        self.tree.commit();
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs::remove_file;
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn open(path: &str, clock: &Rc<Cell<u64>>) -> TtlDB {
+        let mut db = TtlDB::open(path).unwrap();
+        let clock = clock.clone();
+        db.set_clock(Box::new(move || clock.get()));
+        db
+    }
+
+    #[test]
+    fn test_ttl() {
+        let _ = remove_file("test_ttl.db");
+        let clock = Rc::new(Cell::new(1000));
+        let mut db = open("test_ttl.db", &clock);
+        for i in 0..100u32 {
+            db.insert_with_ttl(&i.to_be_bytes(), &[0xac; 50], Duration::from_millis(i as u64 * 10));
+        }
+        db.insert(b"forever", b"1");
+        assert_eq!(db.ttl(&50u32.to_be_bytes()), Some(Duration::from_millis(500)));
+        assert_eq!(db.ttl(b"forever"), None);
+
+        // hidden as soon as the deadline passes, before any sweep
+        clock.set(1500);
+        assert_eq!(db.get(&50u32.to_be_bytes()), None);
+        assert_eq!(db.ttl(&50u32.to_be_bytes()), None);
+        assert_eq!(db.get(&51u32.to_be_bytes()), Some(vec![0xac; 50]));
+        assert_eq!(db.ttl(&51u32.to_be_bytes()), Some(Duration::from_millis(10)));
+        assert_eq!(db.scan(&[0x00]).count(), 50);
+        assert_eq!(db.scan(&[0x00]).next().unwrap().0, 51u32.to_be_bytes());
+        assert!(!db.delete(&10u32.to_be_bytes()));
+        drop(db);
+
+        // the deadlines outlive a reopen
+        let mut db = open("test_ttl.db", &clock);
+        assert_eq!(db.get(&50u32.to_be_bytes()), None);
+        assert_eq!(db.sweep(20), 20);
+        assert_eq!(db.sweep(20), 20);
+        // 10 was deleted already
+        assert_eq!(db.sweep(20), 10);
+        assert_eq!(db.sweep(20), 0);
+        clock.set(u64::MAX);
+        assert_eq!(db.sweep(1000), 49);
+        assert_eq!(db.scan(&[0x00]).collect::<Vec<_>>(), vec![(b"forever".to_vec(), b"1".to_vec())]);
+        assert_eq!(db.tree.check(), Ok(()));
+        // nothing left of the index
+        assert_eq!(db.tree.count(), 1);
+    }
+
+    #[test]
+    fn test_ttl_overwrite() {
+        let _ = remove_file("test_ttl_overwrite.db");
+        let clock = Rc::new(Cell::new(1000));
+        let mut db = open("test_ttl_overwrite.db", &clock);
+        db.insert_with_ttl(b"a", b"1", Duration::from_millis(100));
+        db.insert_with_ttl(b"b", b"1", Duration::from_millis(100));
+        // a plain insert drops the ttl, a new ttl moves the deadline
+        db.insert(b"a", b"2");
+        db.insert_with_ttl(b"b", b"2", Duration::from_millis(500));
+        clock.set(1200);
+        assert_eq!(db.sweep(10), 0);
+        assert_eq!(db.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b"), Some(b"2".to_vec()));
+        clock.set(1600);
+        assert_eq!(db.sweep(10), 1);
+        assert_eq!(db.get(b"b"), None);
+
+        // an expired key comes back when inserted again
+        db.insert_with_ttl(b"c", b"1", Duration::from_millis(100));
+        clock.set(1800);
+        db.insert(b"c", b"2");
+        assert_eq!(db.sweep(10), 0);
+        assert_eq!(db.get(b"c"), Some(b"2".to_vec()));
+        assert_eq!(db.tree.count(), 2);
+
+        db.insert_with_ttl(b"d", b"1", Duration::MAX);
+        assert_eq!(db.ttl(b"d"), Some(Duration::from_millis(u64::MAX - 1800)));
+        clock.set(u64::MAX - 1);
+        assert_eq!(db.get(b"d"), Some(b"1".to_vec()));
+    }
+}
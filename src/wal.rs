@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::b_tree::BTree;
+use crate::kv::KV;
+
+// log record: | crc | len | op | k_len | key | val |, crc covers everything after itself
+const RECORD_HEADER: usize = 11;
+const OP_PUT: u8 = 1;
+const OP_DEL: u8 = 2;
+const DEFAULT_CHECKPOINT_SIZE: u64 = 1 << 20;
+
+// a put, or a delete when the val is None
+pub type Record = (Vec<u8>, Option<Vec<u8>>);
+
+pub struct Wal {
+    file: File,
+    size: u64,
+}
+
+impl Wal {
+    // replays the log, a torn or corrupt tail is cut off
+    pub fn open(path: &str) -> Result<(Wal, Vec<Record>), String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path).map_err(|e| e.to_string())?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while let Some((record, len)) = decode(&data[pos..]) {
+            records.push(record);
+            pos += len;
+        }
+        if pos < data.len() {
+            file.set_len(pos as u64).map_err(|e| e.to_string())?;
+            file.sync_all().map_err(|e| e.to_string())?;
+        }
+        file.seek(SeekFrom::Start(pos as u64)).map_err(|e| e.to_string())?;
+        Ok((Wal { file, size: pos as u64 }, records))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // durable once this returns
+    pub fn append(&mut self, key: &[u8], val: Option<&[u8]>) -> Result<(), String> {
+        let record = encode(key, val);
+        self.file.write_all(&record).map_err(|e| e.to_string())?;
+        self.file.sync_data().map_err(|e| e.to_string())?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.file.set_len(0).map_err(|e| e.to_string())?;
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        self.file.sync_all().map_err(|e| e.to_string())?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn encode(key: &[u8], val: Option<&[u8]>) -> Vec<u8> {
+    let body_len = RECORD_HEADER - 8 + key.len() + val.map_or(0, |v| v.len());
+    let mut record = Vec::with_capacity(8 + body_len);
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&(body_len as u32).to_le_bytes());
+    record.push(if val.is_some() { OP_PUT } else { OP_DEL });
+    record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(val.unwrap_or(&[]));
+    let crc = crc32(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+// the record and its size, None at the end of the valid log
+fn decode(data: &[u8]) -> Option<(Record, usize)> {
+    if data.len() < RECORD_HEADER {
+        return None;
+    }
+    let crc = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let body_len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    if body_len < RECORD_HEADER - 8 || data.len() < 8 + body_len || crc32(&data[4..8 + body_len]) != crc {
+        return None;
+    }
+    let k_len = u16::from_le_bytes(data[9..11].try_into().unwrap()) as usize;
+    if RECORD_HEADER + k_len > 8 + body_len {
+        return None;
+    }
+    let key = data[RECORD_HEADER..RECORD_HEADER + k_len].to_vec();
+    let val = match data[8] {
+        OP_PUT => Some(data[RECORD_HEADER + k_len..8 + body_len].to_vec()),
+        OP_DEL => None,
+        _ => return None,
+    };
+    Some(((key, val), 8 + body_len))
+}
+
+// crc-32 (ieee)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// commits only fsync the log, the tree catches up at checkpoints
+pub struct WalDB {
+    tree: BTree,
+    wal: Wal,
+    // committed to the log, not yet in the tree
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    checkpoint_size: u64,
+}
+
+impl WalDB {
+    // the log lives next to the db file, at `<path>.wal`
+    pub fn open(path: &str) -> Result<WalDB, String> {
+        let tree = BTree::new(Box::new(KV::new(String::from(path))?));
+        let (wal, records) = Wal::open(&format!("{}.wal", path))?;
+        let pending = records.into_iter().collect();
+        Ok(WalDB {
+            tree,
+            wal,
+            pending,
+            checkpoint_size: DEFAULT_CHECKPOINT_SIZE,
+        })
+    }
+
+    // log size that triggers a checkpoint
+    pub fn set_checkpoint_size(&mut self, size: u64) {
+        self.checkpoint_size = size;
+    }
+
+    pub fn wal_size(&self) -> u64 {
+        self.wal.size()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.pending.get(key) {
+            Some(val) => val.clone(),
+            None => self.tree.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<(), String> {
+        self.check_key(key)?;
+        if val.len() > self.tree.max_val_size() {
+            return Err(format!("val of {} bytes", val.len()));
+        }
+        self.wal.append(key, Some(val))?;
+        self.pending.insert(key.to_vec(), Some(val.to_vec()));
+        self.maybe_checkpoint()
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, String> {
+        self.check_key(key)?;
+        if self.get(key).is_none() {
+            return Ok(false);
+        }
+        self.wal.append(key, None)?;
+        self.pending.insert(key.to_vec(), None);
+        self.maybe_checkpoint()?;
+        Ok(true)
+    }
+
+    // a record the tree cannot take never goes to the log, it would fail at replay
+    fn check_key(&self, key: &[u8]) -> Result<(), String> {
+        if key.is_empty() || key.len() > self.tree.max_key_size() {
+            return Err(format!("key of {} bytes", key.len()));
+        }
+        Ok(())
+    }
+
+    fn maybe_checkpoint(&mut self) -> Result<(), String> {
+        if self.wal.size() >= self.checkpoint_size {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // apply the log to the tree in one commit, then start a new log;
+    // a crash in between replays records that are already applied, which is harmless
+    pub fn checkpoint(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return self.wal.reset();
+        }
+        self.tree.begin();
+        for (key, val) in std::mem::take(&mut self.pending) {
+            match val {
+                Some(val) => self.tree.insert(&key, &val),
+                None => {
+                    self.tree.delete(&key);
+                }
+            }
+        }
+        self.tree.commit();
+        self.wal.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+
+    fn open(path: &str) -> WalDB {
+        WalDB::open(path).unwrap()
+    }
+
+    fn clean(path: &str) {
+        let _ = remove_file(path);
+        let _ = remove_file(format!("{}.wal", path));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_record() {
+        let record = encode(b"key", Some(b"val"));
+        assert_eq!(decode(&record), Some(((b"key".to_vec(), Some(b"val".to_vec())), record.len())));
+        let record = encode(b"key", None);
+        assert_eq!(decode(&record), Some(((b"key".to_vec(), None), record.len())));
+        assert_eq!(decode(&record[..record.len() - 1]), None);
+        let mut bad = record.clone();
+        bad[9] ^= 0x01;
+        assert_eq!(decode(&bad), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        clean("test_wal_replay.db");
+        let mut db = open("test_wal_replay.db");
+        db.insert(b"a", b"1").unwrap();
+        db.insert(b"b", b"2").unwrap();
+        db.insert(b"a", b"3").unwrap();
+        assert!(db.delete(b"b").unwrap());
+        assert!(!db.delete(b"c").unwrap());
+        assert_eq!(db.get(b"a"), Some(b"3".to_vec()));
+        // bad sizes are refused before they reach the log
+        let size = db.wal_size();
+        assert!(db.insert(b"", b"1").is_err());
+        assert!(db.insert(&[0xac; 5000], b"1").is_err());
+        assert!(db.insert(b"d", &[0xac; 5000]).is_err());
+        assert!(db.delete(&[0xac; 5000]).is_err());
+        assert_eq!(db.wal_size(), size);
+        drop(db);
+
+        // nothing reached the tree yet
+        let tree = BTree::new(Box::new(KV::new(String::from("test_wal_replay.db")).unwrap()));
+        assert_eq!(tree.get(b"a"), None);
+        drop(tree);
+
+        let db = open("test_wal_replay.db");
+        assert_eq!(db.get(b"a"), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b"), None);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        clean("test_wal_checkpoint.db");
+        let mut db = open("test_wal_checkpoint.db");
+        for i in 0..100u32 {
+            db.insert(&i.to_be_bytes(), &[0xca; 100]).unwrap();
+        }
+        db.delete(&7u32.to_be_bytes()).unwrap();
+        db.checkpoint().unwrap();
+        assert_eq!(db.wal_size(), 0);
+        drop(db);
+
+        let tree = BTree::new(Box::new(KV::new(String::from("test_wal_checkpoint.db")).unwrap()));
+        assert_eq!(tree.scan(&[0x00]).count(), 99);
+        assert_eq!(tree.get(&7u32.to_be_bytes()), None);
+        assert_eq!(tree.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_auto_checkpoint() {
+        clean("test_wal_auto.db");
+        let mut db = open("test_wal_auto.db");
+        db.set_checkpoint_size(4096);
+        for i in 0..100u32 {
+            db.insert(&i.to_be_bytes(), &[0xca; 100]).unwrap();
+            assert!(db.wal_size() < 4096);
+        }
+        drop(db);
+        let db = open("test_wal_auto.db");
+        for i in 0..100u32 {
+            assert_eq!(db.get(&i.to_be_bytes()), Some(vec![0xca; 100]));
+        }
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        clean("test_wal_torn.db");
+        let mut db = open("test_wal_torn.db");
+        db.insert(b"a", b"1").unwrap();
+        db.insert(b"b", b"2").unwrap();
+        let size = db.wal_size();
+        drop(db);
+
+        // half of a third record made it to disk
+        let mut file = OpenOptions::new().append(true).open("test_wal_torn.db.wal").unwrap();
+        file.write_all(&encode(b"c", Some(b"3"))[..10]).unwrap();
+        drop(file);
+
+        let mut db = open("test_wal_torn.db");
+        assert_eq!(db.wal_size(), size);
+        assert_eq!(db.get(b"b"), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"c"), None);
+        db.insert(b"c", b"4").unwrap();
+        drop(db);
+        assert_eq!(open("test_wal_torn.db").get(b"c"), Some(b"4".to_vec()));
+    }
+}